@@ -0,0 +1,90 @@
+//! Compares the registry's name map with the `FxHashMap<Vec<u8>, _>` it
+//! replaced, doing the same lookup-or-insert of an `Aggregation` per line as
+//! the workers.
+//!
+//! `cargo bench --bench registry [-- measurements.txt]` reads the station
+//! names from a measurements file, or makes up 10,000 of them.
+
+use std::{hint::black_box, time::Instant};
+
+use fxhash::FxHashMap;
+use one_billion_row_challenge::{Aggregation, NameMap};
+use rand::{distributions::Alphanumeric, prelude::*};
+
+const LINES: usize = 50_000_000;
+
+fn aggregation(value: i32) -> Aggregation {
+    let mut aggregation = Aggregation::new(1, false);
+    aggregation.update(value);
+    aggregation
+}
+
+fn checksum<'a>(aggregations: impl Iterator<Item = &'a Aggregation>) -> i64 {
+    aggregations
+        .map(|aggregation| {
+            aggregation.min() as i64
+                + aggregation.max() as i64
+                + aggregation.sum()
+                + aggregation.count() as i64
+        })
+        .sum()
+}
+
+fn main() -> anyhow::Result<()> {
+    let path = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let mut rng = StdRng::seed_from_u64(0);
+    let names: Vec<Vec<u8>> = match &path {
+        Some(path) => {
+            let data = std::fs::read(path)?;
+            let mut names = data
+                .split(|&b| b == b'\n')
+                .filter_map(|line| Some(&line[..memchr::memrchr(b';', line)?]))
+                .map(<[u8]>::to_vec)
+                .collect::<Vec<_>>();
+            names.sort_unstable();
+            names.dedup();
+            names
+        }
+        None => (0..10_000)
+            .map(|_| {
+                let len = rng.gen_range(3..=26);
+                (&mut rng).sample_iter(Alphanumeric).take(len).collect()
+            })
+            .collect(),
+    };
+    let lines: Vec<(&[u8], i32)> = (0..LINES)
+        .map(|_| {
+            let name = names.choose(&mut rng).expect("There are names");
+            (&name[..], rng.gen_range(-999..=999))
+        })
+        .collect();
+    eprintln!("{} stations, {LINES} lines", names.len());
+
+    for _ in 0..3 {
+        let start = Instant::now();
+        let mut map = FxHashMap::<Vec<u8>, Aggregation>::default();
+        for &(name, value) in &lines {
+            match map.get_mut(name) {
+                Some(aggregation) => aggregation.update(value),
+                None => {
+                    map.insert(name.to_vec(), aggregation(value));
+                }
+            }
+        }
+        let expected = checksum(black_box(&map).values());
+        eprintln!("FxHashMap: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let mut map = NameMap::<Aggregation>::default();
+        for &(name, value) in &lines {
+            match map.get_mut(name) {
+                Some(aggregation) => aggregation.update(value),
+                None => map.insert(name, aggregation(value)),
+            }
+        }
+        let actual = checksum(black_box(&map).iter().map(|(_, aggregation)| aggregation));
+        eprintln!("NameMap:   {:?}", start.elapsed());
+        assert_eq!(actual, expected);
+    }
+    Ok(())
+}
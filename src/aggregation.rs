@@ -0,0 +1,328 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// Lowest value the dense part of a histogram counts, -99.9 at the default
+/// scale.
+const DENSE_MIN: i32 = -999;
+/// One dense slot per value from -99.9 to 99.9 at the default scale.
+const DENSE_SLOTS: usize = 1999;
+/// Heap bytes of the dense part of a histogram, which every one has however
+/// few values it counts.
+pub const HISTOGRAM_SIZE: usize = DENSE_SLOTS * size_of::<u64>();
+
+/// How [`Aggregation::rounded_mean`] breaks ties between the two nearest
+/// values at the aggregation's scale.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Rounding {
+    /// Toward +∞, like `Math.round` in the 1BRC reference.
+    #[default]
+    HalfUp,
+    /// To the even value, IEEE 754's default.
+    HalfEven,
+    /// Away from zero.
+    HalfAway,
+}
+
+/// Count of the temperatures from `low` up to but excluding `high`, in the
+/// aggregation's fixed-point units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bucket {
+    pub low: i64,
+    pub high: i64,
+    pub count: u64,
+}
+
+/// Count of every temperature seen by one station.
+#[derive(Debug, Clone)]
+struct Histogram {
+    /// Counts of the classic 1BRC range, 16 KiB of them. 64 bits per slot,
+    /// like the count, so hot stations can't wrap them around.
+    dense: Box<[u64]>,
+    /// Counts of anything outside of it.
+    sparse: BTreeMap<i32, u64>,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            dense: vec![0; DENSE_SLOTS].into_boxed_slice(),
+            sparse: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, value: i32) {
+        match self
+            .dense
+            .get_mut(value.wrapping_sub(DENSE_MIN) as u32 as usize)
+        {
+            Some(count) => *count += 1,
+            None => *self.sparse.entry(value).or_default() += 1,
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (count, other) in self.dense.iter_mut().zip(other.dense.iter()) {
+            *count += other;
+        }
+        for (&value, &count) in &other.sparse {
+            *self.sparse.entry(value).or_default() += count;
+        }
+    }
+
+    /// Every value seen and its count, in increasing order.
+    fn counts(&self) -> impl Iterator<Item = (i32, u64)> + '_ {
+        let below = self.sparse.range(..DENSE_MIN);
+        let dense = self
+            .dense
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(i, &count)| (i as i32 + DENSE_MIN, count));
+        let above = self.sparse.range(DENSE_MIN + DENSE_SLOTS as i32..);
+        let copied = |(&value, &count): (&i32, &u64)| (value, count);
+        below.map(copied).chain(dense).chain(above.map(copied))
+    }
+}
+
+/// Running min/max/mean/variance of the temperatures seen for a single station.
+///
+/// Temperatures are fixed-point integers with [`Aggregation::scale`] decimal
+/// places, by default tenths of a degree, so `123` means `12.3`.
+/// The sum and count are 64-bit; overflowing them panics in debug builds and
+/// with `--profile checked`.
+#[derive(Debug, Clone)]
+pub struct Aggregation {
+    min: i32,
+    max: i32,
+    sum: i64,
+    sum_of_squares: u128,
+    count: u64,
+    scale: u8,
+    /// Only kept when percentiles or histograms were asked for.
+    histogram: Option<Box<Histogram>>,
+}
+
+impl Aggregation {
+    /// An empty aggregation of values with `scale` decimal places, keeping a
+    /// histogram if `histogram`.
+    pub fn new(scale: u8, histogram: bool) -> Self {
+        Self {
+            min: i32::MAX,
+            max: i32::MIN,
+            sum: 0,
+            sum_of_squares: 0,
+            count: 0,
+            scale,
+            histogram: histogram.then(|| Box::new(Histogram::new())),
+        }
+    }
+
+    pub fn update(&mut self, value: i32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as i64;
+        self.sum_of_squares += (value as i64).pow(2) as u128;
+        self.count += 1;
+        if let Some(histogram) = &mut self.histogram {
+            histogram.record(value);
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &Self) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.sum_of_squares += other.sum_of_squares;
+        self.count += other.count;
+        if let (Some(histogram), Some(other)) = (&mut self.histogram, &other.histogram) {
+            histogram.merge(other);
+        }
+    }
+
+    pub(crate) fn has_histogram(&self) -> bool {
+        self.histogram.is_some()
+    }
+
+    /// Rough estimate of the heap memory held besides the aggregation itself.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.histogram.as_ref().map_or(0, |histogram| {
+            // B-tree nodes are about two thirds full.
+            size_of::<Histogram>()
+                + size_of_val(&*histogram.dense)
+                + histogram.sparse.len() * size_of::<(i32, u64)>() * 3 / 2
+        })
+    }
+
+    pub fn min(&self) -> i32 {
+        self.min
+    }
+
+    pub fn max(&self) -> i32 {
+        self.max
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> i64 {
+        self.sum
+    }
+
+    /// Number of decimal places of the fixed-point values.
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    /// Population variance in square degrees.
+    pub fn variance(&self) -> f64 {
+        // n² · variance = n · Σx² - (Σx)², exactly in integers.
+        let count = self.count as i128;
+        let scaled = count * self.sum_of_squares as i128 - (self.sum as i128).pow(2);
+        scaled as f64 / (count * count) as f64 / 10_f64.powi(2 * self.scale as i32)
+    }
+
+    /// Population standard deviation in degrees.
+    pub fn stddev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+
+    /// The mean rounded half up to the aggregation's scale, as in the 1BRC
+    /// reference.
+    pub fn mean(&self) -> i32 {
+        self.rounded_mean(Rounding::HalfUp)
+    }
+
+    /// The mean rounded to the aggregation's scale, breaking ties with
+    /// `rounding`. Exact: the division is done on the integer sum and count.
+    pub fn rounded_mean(&self, rounding: Rounding) -> i32 {
+        let (sum, count) = (self.sum as i128, self.count as i128);
+        // mean = quotient + remainder / count, with 0 <= remainder < count.
+        let quotient = sum.div_euclid(count);
+        let round_up = match (2 * sum.rem_euclid(count)).cmp(&count) {
+            Ordering::Less => false,
+            Ordering::Greater => true,
+            Ordering::Equal => match rounding {
+                Rounding::HalfUp => true,
+                Rounding::HalfEven => quotient % 2 != 0,
+                Rounding::HalfAway => quotient >= 0,
+            },
+        };
+        (quotient + round_up as i128) as i32
+    }
+
+    /// The `percentile`th percentile (0 to 100) by the nearest-rank method:
+    /// the lowest temperature that at least that share of the readings is at
+    /// or below. `None` unless the aggregation kept a histogram.
+    pub fn percentile(&self, percentile: f64) -> Option<i32> {
+        let histogram = self.histogram.as_ref()?;
+        // In millionths of a percent, so that the rank of 7 or 99.9 is exact.
+        let millionths = (percentile * 1e6).round() as u128;
+        let rank = (millionths * self.count as u128).div_ceil(100_000_000) as u64;
+        let rank = rank.clamp(1, self.count);
+        let mut seen = 0;
+        histogram
+            .counts()
+            .find(|&(_, count)| {
+                seen += count;
+                seen >= rank
+            })
+            .map(|(value, _)| value)
+    }
+
+    /// Counts in `width` units wide buckets aligned on zero, in increasing
+    /// order and leaving out empty ones. `None` unless the aggregation kept a
+    /// histogram.
+    pub fn buckets(&self, width: u32) -> Option<Vec<Bucket>> {
+        let histogram = self.histogram.as_ref()?;
+        let width = width.max(1) as i64;
+        let mut buckets: Vec<Bucket> = Vec::new();
+        for (value, count) in histogram.counts() {
+            let low = (value as i64).div_euclid(width) * width;
+            match buckets.last_mut() {
+                Some(bucket) if bucket.low == low => bucket.count += count,
+                _ => buckets.push(Bucket {
+                    low,
+                    high: low + width,
+                    count,
+                }),
+            }
+        }
+        Some(buckets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregate(values: impl IntoIterator<Item = i32>, scale: u8) -> Aggregation {
+        let mut aggregation = Aggregation::new(scale, true);
+        for value in values {
+            aggregation.update(value);
+        }
+        aggregation
+    }
+
+    fn rounded_means(values: impl IntoIterator<Item = i32>, scale: u8) -> [i32; 3] {
+        let aggregation = aggregate(values, scale);
+        [Rounding::HalfUp, Rounding::HalfEven, Rounding::HalfAway]
+            .map(|rounding| aggregation.rounded_mean(rounding))
+    }
+
+    #[test]
+    fn rounds_ties_by_mode() {
+        // Half up, half even, half away, in tenths.
+        assert_eq!(rounded_means([12, 13], 1), [13, 12, 13]);
+        assert_eq!(rounded_means([13, 14], 1), [14, 14, 14]);
+        assert_eq!(rounded_means([-12, -13], 1), [-12, -12, -13]);
+        assert_eq!(rounded_means([-13, -14], 1), [-13, -14, -14]);
+        assert_eq!(rounded_means([-1, -2], 1), [-1, -2, -2]);
+        assert_eq!(rounded_means([0, -5], 1), [-2, -2, -3]);
+        // -58.225 in hundredths.
+        assert_eq!(rounded_means([-5822, -5823], 2), [-5822, -5822, -5823]);
+    }
+
+    #[test]
+    fn rounds_non_ties_to_nearest() {
+        assert_eq!(rounded_means([-12, -13, -13], 1), [-13; 3]);
+        assert_eq!(rounded_means([-12, -12, -13], 1), [-12; 3]);
+        assert_eq!(rounded_means([12, 12, 13], 1), [12; 3]);
+        assert_eq!(rounded_means([-1, -1, -1, 0], 1), [-1; 3]);
+        assert_eq!(rounded_means([-1, 0, 0, 0], 1), [0; 3]);
+    }
+
+    #[test]
+    fn matches_reference_samples() {
+        // samples/measurements-1.txt: Kunming=19.8/19.8/19.8
+        let kunming = aggregate([198], 1);
+        assert_eq!(
+            (kunming.min(), kunming.mean(), kunming.max()),
+            (198, 198, 198)
+        );
+        // samples/measurements-2.txt: Bosaso=-15.0/1.3/20.0
+        let bosaso = aggregate([50, 200, -50, -150], 1);
+        assert_eq!((bosaso.min(), bosaso.mean(), bosaso.max()), (-150, 13, 200));
+        // samples/measurements-2.txt: Petropavlovsk-Kamchatsky=-9.5/0.0/9.5
+        let petropavlovsk = aggregate([95, -95], 1);
+        assert_eq!(
+            (
+                petropavlovsk.min(),
+                petropavlovsk.mean(),
+                petropavlovsk.max()
+            ),
+            (-95, 0, 95)
+        );
+    }
+
+    #[test]
+    fn percentile_ranks_are_exact() {
+        let aggregation = aggregate(1..=100, 0);
+        let percentiles = [0.0, 1.0, 7.0, 14.0, 28.0, 50.0, 56.0, 99.0, 100.0]
+            .map(|percentile| aggregation.percentile(percentile));
+        assert_eq!(percentiles, [1, 1, 7, 14, 28, 50, 56, 99, 100].map(Some));
+        let aggregation = aggregate(1..=1000, 0);
+        assert_eq!(aggregation.percentile(99.9), Some(999));
+        assert_eq!(aggregation.percentile(0.05), Some(1));
+        assert_eq!(aggregation.percentile(0.15), Some(2));
+    }
+}
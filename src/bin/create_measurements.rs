@@ -25,6 +25,8 @@ struct Args {
     output_file: PathBuf,
 }
 
+// Unreadable lines are skipped rather than ending the list.
+#[allow(clippy::lines_filter_map_ok)]
 fn build_weather_station_name_list(input_file: &PathBuf) -> Result<Vec<String>> {
     let file = File::open(input_file)
         .with_context(|| format!("Failed to open weather stations file: {:?}", input_file))?;
@@ -0,0 +1,181 @@
+use std::{io, sync::Arc};
+
+use arrow_array::{ArrayRef, Decimal128Array, Float64Array, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::{ArrowError, DataType, Schema};
+
+use crate::{output::Field, Aggregation, Bucket, Column};
+
+const TEMPERATURE_PRECISION: u8 = 10;
+const SUM_PRECISION: u8 = 20;
+/// Bucket bounds can reach a bucket width past the `i32` range.
+const BUCKET_PRECISION: u8 = 11;
+
+/// The decimal places of the aggregations, which all share them.
+fn decimal_scale(name_aggregations: &[(Vec<u8>, Aggregation)]) -> i8 {
+    name_aggregations
+        .first()
+        .map_or(1, |(_, aggregation)| aggregation.scale() as i8)
+}
+
+/// One row per station: utf8 name, decimal temperatures, percentiles and sum,
+/// u64 count and f64 spread.
+fn record_batch(
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    fields: &[Field],
+) -> Result<RecordBatch, ArrowError> {
+    let scale = decimal_scale(name_aggregations);
+    let decimal_column = |precision: u8, value: &dyn Fn(&Aggregation) -> i64| {
+        let array = name_aggregations
+            .iter()
+            .map(|(_, aggregation)| value(aggregation) as i128)
+            .collect::<Decimal128Array>()
+            .with_precision_and_scale(precision, scale)?;
+        let data_type = DataType::Decimal128(precision, scale);
+        Ok::<_, ArrowError>((data_type, Arc::new(array) as ArrayRef))
+    };
+    let float_column = |value: fn(&Aggregation) -> f64| {
+        let array = name_aggregations
+            .iter()
+            .map(|(_, aggregation)| value(aggregation))
+            .collect::<Float64Array>();
+        (DataType::Float64, Arc::new(array) as ArrayRef)
+    };
+    let (schema_fields, arrays): (Vec<_>, Vec<_>) = fields
+        .iter()
+        .map(|&field| {
+            let column = match field {
+                Field::Column(column) => column,
+                Field::Mean(_) | Field::Percentile(_) => {
+                    let (data_type, array) = decimal_column(TEMPERATURE_PRECISION, &|a| {
+                        field.temperature(a).expect("Not a column").into()
+                    })?;
+                    let schema_field = arrow_schema::Field::new(field.header(), data_type, false);
+                    return Ok((schema_field, array));
+                }
+            };
+            let (data_type, array) = match column {
+                Column::Name => (
+                    DataType::Utf8,
+                    Arc::new(
+                        name_aggregations
+                            .iter()
+                            .map(|(name, _)| Some(String::from_utf8_lossy(name)))
+                            .collect::<StringArray>(),
+                    ) as ArrayRef,
+                ),
+                Column::Min => decimal_column(TEMPERATURE_PRECISION, &|a| a.min().into())?,
+                Column::Mean => decimal_column(TEMPERATURE_PRECISION, &|a| a.mean().into())?,
+                Column::Max => decimal_column(TEMPERATURE_PRECISION, &|a| a.max().into())?,
+                Column::Sum => decimal_column(SUM_PRECISION, &Aggregation::sum)?,
+                Column::Variance => float_column(Aggregation::variance),
+                Column::Stddev => float_column(Aggregation::stddev),
+                Column::Count => (
+                    DataType::UInt64,
+                    Arc::new(
+                        name_aggregations
+                            .iter()
+                            .map(|(_, aggregation)| aggregation.count())
+                            .collect::<UInt64Array>(),
+                    ) as ArrayRef,
+                ),
+            };
+            let schema_field = arrow_schema::Field::new(field.header(), data_type, false);
+            Ok((schema_field, array))
+        })
+        .collect::<Result<Vec<_>, ArrowError>>()?
+        .into_iter()
+        .unzip();
+    RecordBatch::try_new(Arc::new(Schema::new(schema_fields)), arrays)
+}
+
+/// One row per station and histogram bucket: utf8 name, decimal bucket
+/// bounds and u64 count.
+fn bucket_record_batch(
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    width: u32,
+) -> Result<RecordBatch, ArrowError> {
+    let rows = name_aggregations
+        .iter()
+        .flat_map(|(name, aggregation)| {
+            let buckets = aggregation
+                .buckets(width)
+                .expect("Checked by write_results");
+            buckets.into_iter().map(move |bucket| (name, bucket))
+        })
+        .collect::<Vec<_>>();
+    let names = rows
+        .iter()
+        .map(|(name, _)| Some(String::from_utf8_lossy(name)))
+        .collect::<StringArray>();
+    let scale = decimal_scale(name_aggregations);
+    let bound = |value: fn(&Bucket) -> i64| {
+        rows.iter()
+            .map(|(_, bucket)| value(bucket) as i128)
+            .collect::<Decimal128Array>()
+            .with_precision_and_scale(BUCKET_PRECISION, scale)
+    };
+    let lows = bound(|bucket| bucket.low)?;
+    let highs = bound(|bucket| bucket.high)?;
+    let counts = rows
+        .iter()
+        .map(|(_, bucket)| bucket.count)
+        .collect::<UInt64Array>();
+    let decimal = DataType::Decimal128(BUCKET_PRECISION, scale);
+    let schema = Schema::new(vec![
+        arrow_schema::Field::new("station", DataType::Utf8, false),
+        arrow_schema::Field::new("bucket_lo", decimal.clone(), false),
+        arrow_schema::Field::new("bucket_hi", decimal, false),
+        arrow_schema::Field::new("count", DataType::UInt64, false),
+    ]);
+    let arrays: Vec<ArrayRef> = vec![
+        Arc::new(names),
+        Arc::new(lows),
+        Arc::new(highs),
+        Arc::new(counts),
+    ];
+    RecordBatch::try_new(Arc::new(schema), arrays)
+}
+
+/// The per-station columns, or the histograms if given a bucket width.
+fn batch(
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    fields: &[Field],
+    histogram: Option<u32>,
+) -> io::Result<RecordBatch> {
+    match histogram {
+        Some(width) => bucket_record_batch(name_aggregations, width),
+        None => record_batch(name_aggregations, fields),
+    }
+    .map_err(io::Error::other)
+}
+
+/// Writes the results as an Arrow IPC file.
+pub(crate) fn write_arrow(
+    writer: &mut impl io::Write,
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    fields: &[Field],
+    histogram: Option<u32>,
+) -> io::Result<()> {
+    let batch = batch(name_aggregations, fields, histogram)?;
+    let mut ipc_writer = arrow_ipc::writer::FileWriter::try_new(writer, &batch.schema())
+        .map_err(io::Error::other)?;
+    ipc_writer.write(&batch).map_err(io::Error::other)?;
+    ipc_writer.finish().map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Writes the results as a Parquet file. The file is built in memory first,
+/// as the Parquet writer needs to own a `Send` writer.
+pub(crate) fn write_parquet(
+    writer: &mut impl io::Write,
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    fields: &[Field],
+    histogram: Option<u32>,
+) -> io::Result<()> {
+    let batch = batch(name_aggregations, fields, histogram)?;
+    let mut parquet_writer = parquet::arrow::ArrowWriter::try_new(Vec::new(), batch.schema(), None)
+        .map_err(io::Error::other)?;
+    parquet_writer.write(&batch).map_err(io::Error::other)?;
+    let bytes = parquet_writer.into_inner().map_err(io::Error::other)?;
+    writer.write_all(&bytes)
+}
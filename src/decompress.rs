@@ -0,0 +1,103 @@
+use std::{
+    io::{self, Read},
+    sync::mpsc,
+    thread,
+};
+
+use crate::fill;
+
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Recognizes the format from the magic bytes at the start of the input.
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        match magic {
+            [0x1f, 0x8b, ..] => Some(Compression::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Compression::Zstd),
+            [0x04, 0x22, 0x4d, 0x18, ..] => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// Wraps `reader` so that gzip, zstd or lz4 input is decompressed on a
+/// dedicated thread. Anything else is passed through untouched.
+pub fn decompress(mut reader: impl Read + Send + 'static) -> io::Result<Box<dyn Read + Send>> {
+    let mut magic = [0_u8; 4];
+    let magic_len = fill(&mut reader, &mut magic)?;
+    let reader = io::Cursor::new(magic).take(magic_len as u64).chain(reader);
+    let Some(compression) = Compression::detect(&magic[..magic_len]) else {
+        return Ok(Box::new(reader));
+    };
+    let (sender, receiver) = mpsc::sync_channel(CHUNKS_IN_FLIGHT);
+    thread::spawn(move || {
+        let mut decoder: Box<dyn Read> = match compression {
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            Compression::Zstd => match zstd::Decoder::new(reader) {
+                Ok(decoder) => Box::new(decoder),
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            },
+            Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+        };
+        loop {
+            let mut chunk = vec![0_u8; CHUNK_SIZE];
+            let message = fill(&mut decoder, &mut chunk).map(|len| {
+                chunk.truncate(len);
+                chunk
+            });
+            let done = !matches!(&message, Ok(chunk) if !chunk.is_empty());
+            if sender.send(message).is_err() || done {
+                return;
+            }
+        }
+    });
+    Ok(Box::new(ChannelReader {
+        receiver,
+        chunk: Vec::new(),
+        position: 0,
+        done: false,
+    }))
+}
+
+/// Reads the chunks sent by the decompression thread.
+struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.done = self.chunk.is_empty();
+                }
+                Err(mpsc::RecvError) => {
+                    return Err(io::Error::other("Decompression thread panicked"))
+                }
+            }
+            self.position = 0;
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
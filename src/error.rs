@@ -0,0 +1,80 @@
+use std::{fmt, io};
+
+/// Why a line was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// It doesn't match `name;temperature`, or the temperature doesn't fit.
+    Format,
+    /// Its station name is not valid UTF-8, under
+    /// [`Utf8Validation::Reject`](crate::Utf8Validation::Reject).
+    InvalidUtf8,
+}
+
+/// A rejected line.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// Index of the input the line came from, see
+    /// [`Aggregator::end_of_input`](crate::Aggregator::end_of_input).
+    pub input: usize,
+    /// Byte offset of the start of the line in the input.
+    pub offset: u64,
+    /// One-based line number.
+    pub line: u64,
+    /// The offending line, without its newline.
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.kind {
+            ParseErrorKind::Format => "Invalid line format",
+            ParseErrorKind::InvalidUtf8 => "Station name is not valid UTF-8",
+        };
+        write!(
+            f,
+            "{reason} on line {} (byte offset {}): {:?}",
+            self.line,
+            self.offset,
+            String::from_utf8_lossy(&self.bytes)
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => e.source(),
+            Error::Parse(e) => e.source(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
@@ -0,0 +1,33 @@
+use fxhash::FxHashSet;
+use regex::bytes::Regex;
+
+/// Which readings to aggregate. Everything passes by default.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Filter {
+    pub station_regex: Option<Regex>,
+    pub stations: Option<FxHashSet<Vec<u8>>>,
+    /// Inclusive bounds in fixed-point units.
+    pub min_temperature: Option<i32>,
+    pub max_temperature: Option<i32>,
+}
+
+impl Filter {
+    pub fn filters_names(&self) -> bool {
+        self.station_regex.is_some() || self.stations.is_some()
+    }
+
+    pub fn accepts_name(&self, name: &[u8]) -> bool {
+        self.station_regex
+            .as_ref()
+            .is_none_or(|regex| regex.is_match(name))
+            && self
+                .stations
+                .as_ref()
+                .is_none_or(|stations| stations.contains(name))
+    }
+
+    pub fn accepts_temperature(&self, temperature: i32) -> bool {
+        self.min_temperature.is_none_or(|min| temperature >= min)
+            && self.max_temperature.is_none_or(|max| temperature <= max)
+    }
+}
@@ -0,0 +1,705 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, Read},
+    ops::Range,
+    sync::{atomic::AtomicUsize, mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashSet;
+use unicode_normalization::UnicodeNormalization;
+
+mod aggregation;
+mod columnar;
+mod decompress;
+mod error;
+mod filter;
+mod name_map;
+mod output;
+mod parse;
+mod pool;
+mod sort;
+
+pub use aggregation::{Aggregation, Bucket, Rounding, HISTOGRAM_SIZE};
+pub use decompress::{decompress, Compression};
+pub use error::{Error, ParseError, ParseErrorKind};
+pub use output::{escape_name, write_results, Column, Format, OutputOptions};
+pub use sort::{Collation, SortBy, SortOptions};
+
+#[doc(hidden)]
+pub use name_map::NameMap;
+
+use filter::Filter;
+use parse::{parse_line, Columns, Layout};
+use pool::{Batch, Buffer, Bytes, Pool, WorkerState};
+
+pub const DEFAULT_BUFFER_SIZE: usize = 128 * 1024 * 1024;
+/// Chunks each worker gets of a batch, so that one that falls behind, say on
+/// lines with new names, doesn't hold up the others at the end.
+const CHUNKS_PER_THREAD: usize = 16;
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+/// The most decimal places that still leave room for a units digit in an `i32`.
+pub const MAX_SCALE: u8 = 9;
+
+type Registry = NameMap<Aggregation>;
+
+/// What to do with lines that fail to parse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnError {
+    /// Stop at the first invalid line.
+    #[default]
+    Abort,
+    /// Count invalid lines and otherwise ignore them.
+    Skip,
+    /// Count invalid lines and keep them for reporting.
+    Collect,
+}
+
+/// What to do with station names that are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Utf8Validation {
+    /// Aggregate them and list them in [`Results::invalid_names`].
+    Report,
+    /// Treat their lines as invalid, and also list them.
+    Reject,
+}
+
+/// How the workers treat each line, fixed once the pool is started.
+#[derive(Debug, Clone)]
+struct Settings {
+    on_error: OnError,
+    histograms: bool,
+    scale: u8,
+    layout: Layout,
+    filter: Filter,
+    validate_utf8: Option<Utf8Validation>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            on_error: OnError::default(),
+            histograms: false,
+            scale: 1,
+            layout: Layout::default(),
+            filter: Filter::default(),
+            validate_utf8: None,
+        }
+    }
+}
+
+/// Streaming aggregator over `name;temperature\n` lines, or other layouts
+/// set with [`Aggregator::delimiter`] and [`Aggregator::columns`].
+///
+/// Input can be fed as arbitrary byte chunks with [`Aggregator::feed`] or
+/// pulled from a reader with [`Aggregator::read_from`]; lines split across
+/// chunk boundaries are stitched back together. The work is done by a pool of
+/// long-lived worker threads, started on first use.
+pub struct Aggregator {
+    threads: usize,
+    pool: Option<Pool>,
+    remainder: Vec<u8>,
+    buffer_size: usize,
+    settings: Settings,
+    rollup: Option<u8>,
+    normalize_names: bool,
+    input: usize,
+    offset: u64,
+    submitted: u64,
+    accounted: u64,
+    pending: BTreeMap<u64, ChunkOutcome>,
+    lines: u64,
+    rejected_lines: u64,
+    filtered_lines: u64,
+    errors: Vec<ParseError>,
+    timings: Timings,
+}
+
+/// Final output of an [`Aggregator`].
+#[derive(Debug)]
+pub struct Results {
+    /// Per-station aggregations, sorted by station name bytes unless
+    /// [`Results::sort`] was called.
+    pub name_aggregations: Vec<(Vec<u8>, Aggregation)>,
+    /// Number of lines that failed to parse and were skipped.
+    pub rejected_lines: u64,
+    /// Number of valid lines left out by the station and temperature filters.
+    pub filtered_lines: u64,
+    /// The rejected lines themselves, when collecting them.
+    pub errors: Vec<ParseError>,
+    /// Distinct station names that are not valid UTF-8, sorted, when
+    /// validating them.
+    pub invalid_names: Vec<Vec<u8>>,
+}
+
+impl Results {
+    /// Orders the stations by `options.sort_by`, ties going by name, and
+    /// keeps only the first `options.top` if given, without sorting the rest.
+    pub fn sort(&mut self, options: &SortOptions) {
+        sort::sort(&mut self.name_aggregations, options);
+    }
+}
+
+/// Where the time went, on the calling thread and on each worker.
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    /// Time [`Aggregator::read_from`] spent reading input into buffers.
+    pub reading: Duration,
+    /// Time [`Aggregator::read_from`] spent waiting for the workers to free
+    /// up a buffer.
+    pub waiting: Duration,
+    /// Time each worker spent aggregating.
+    pub busy: Vec<Duration>,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Aggregator {
+    pub fn new() -> Self {
+        Self {
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            pool: None,
+            remainder: Vec::new(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            settings: Settings::default(),
+            rollup: None,
+            normalize_names: false,
+            input: 0,
+            offset: 0,
+            submitted: 0,
+            accounted: 0,
+            pending: BTreeMap::new(),
+            lines: 0,
+            rejected_lines: 0,
+            filtered_lines: 0,
+            errors: Vec::new(),
+            timings: Timings::default(),
+        }
+    }
+
+    /// Sets the number of worker threads. Must be called before any input
+    /// is fed.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// The number of worker threads, by default one per available core.
+    pub fn thread_count(&self) -> usize {
+        self.threads
+    }
+
+    /// Sets the size of each of the two buffers used by
+    /// [`Aggregator::read_from`]. Lines longer than this are rejected.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.settings.on_error = on_error;
+        self
+    }
+
+    /// Checks that station names are valid UTF-8, once per distinct name as
+    /// it is first aggregated. Must be called before any input is fed.
+    pub fn validate_utf8(mut self, validation: Utf8Validation) -> Self {
+        self.settings.validate_utf8 = Some(validation);
+        self
+    }
+
+    /// Sets the number of decimal places temperatures are kept with, up to
+    /// 9. Temperatures with fewer are padded, ones with more are rejected.
+    /// Defaults to 1, which has a fast path for the 1BRC format. Must be
+    /// called before any input is fed.
+    pub fn scale(mut self, scale: u8) -> Self {
+        self.settings.scale = scale.min(MAX_SCALE);
+        self
+    }
+
+    /// Sets the byte between the columns of a line, `;` by default. Must be
+    /// called before any input is fed.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.settings.layout.delimiter = delimiter;
+        self
+    }
+
+    /// Takes the temperature and name from these zero-based columns, which
+    /// allows for extra ones. Several key columns are joined with the
+    /// delimiter into a composite name. Without a value column the
+    /// temperature is the last column. By default the temperature is
+    /// everything after the last delimiter and the name everything before
+    /// it. Must be called before any input is fed.
+    pub fn columns(mut self, key_columns: &[usize], value_column: Option<usize>) -> Self {
+        self.settings.layout.columns = Some(Columns {
+            keys: key_columns.to_vec(),
+            value: value_column,
+        });
+        self
+    }
+
+    /// Only aggregates stations whose name matches `regex`. Must be called
+    /// before any input is fed.
+    pub fn station_regex(mut self, regex: regex::bytes::Regex) -> Self {
+        self.settings.filter.station_regex = Some(regex);
+        self
+    }
+
+    /// Only aggregates these stations. Must be called before any input is
+    /// fed.
+    pub fn stations(mut self, stations: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        self.settings.filter.stations = Some(stations.into_iter().collect());
+        self
+    }
+
+    /// Only aggregates temperatures of at least `min`, in fixed-point units.
+    /// Must be called before any input is fed.
+    pub fn min_temperature(mut self, min: i32) -> Self {
+        self.settings.filter.min_temperature = Some(min);
+        self
+    }
+
+    /// Only aggregates temperatures of at most `max`, in fixed-point units.
+    /// Must be called before any input is fed.
+    pub fn max_temperature(mut self, max: i32) -> Self {
+        self.settings.filter.max_temperature = Some(max);
+        self
+    }
+
+    /// Also aggregates every prefix of the names that ends before
+    /// `separator`, such as the country of `country;station` composite names.
+    /// The rollups are merged from the per-name results and sorted among
+    /// them; a name that is also a prefix is merged into its group.
+    pub fn rollup(mut self, separator: u8) -> Self {
+        self.rollup = Some(separator);
+        self
+    }
+
+    /// Merges the stations whose names are the same once in Unicode
+    /// Normalization Form C, such as a composed and a decomposed `Zürich`,
+    /// under the NFC name. Names that are not valid UTF-8 are left as is.
+    pub fn normalize_names(mut self, normalize_names: bool) -> Self {
+        self.normalize_names = normalize_names;
+        self
+    }
+
+    /// Keeps a histogram of every station's temperatures, which
+    /// [`Aggregation::percentile`] and [`Aggregation::buckets`] need. Each
+    /// worker thread keeps its own, so that is [`HISTOGRAM_SIZE`], about
+    /// 16 KiB, per station and thread. Must be called before any input is fed.
+    pub fn histograms(mut self, histograms: bool) -> Self {
+        self.settings.histograms = histograms;
+        self
+    }
+
+    pub fn timings(&self) -> Timings {
+        let busy = self.pool.as_ref().map_or_else(Vec::new, |pool| {
+            pool.states()
+                .iter()
+                .map(|state| state.lock().expect("Worker state lock poisoned").busy)
+                .collect()
+        });
+        Timings {
+            busy,
+            ..self.timings.clone()
+        }
+    }
+
+    /// Rough estimate of the heap memory currently held by the registries.
+    pub fn registries_memory(&self) -> usize {
+        let Some(pool) = &self.pool else {
+            return 0;
+        };
+        pool.states()
+            .iter()
+            .map(|state| {
+                let registry = &state.lock().expect("Worker state lock poisoned").registry;
+                let heap: usize = registry
+                    .iter()
+                    .map(|(_, aggregation)| aggregation.heap_size())
+                    .sum();
+                registry.table_size() + heap
+            })
+            .sum()
+    }
+
+    /// Aggregates every complete line in `chunk`, keeping a trailing partial
+    /// line around until the next call. The chunk is copied so the workers
+    /// can own it.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.submit_shared(Arc::new(chunk.to_vec()))?;
+        self.wait()
+    }
+
+    /// Reads `reader` to the end, loading the next buffer while the workers
+    /// process the previous one.
+    pub fn read_from(&mut self, mut reader: impl Read) -> Result<(), Error> {
+        let (free_sender, free_receiver) = mpsc::channel();
+        for _ in 0..2 {
+            free_sender
+                .send(vec![0_u8; self.buffer_size])
+                .expect("We hold the receiver");
+        }
+        loop {
+            let start_waiting = Instant::now();
+            let mut data = free_receiver.recv().expect("We hold a sender");
+            self.timings.waiting += start_waiting.elapsed();
+            if data.len() <= self.remainder.len() {
+                data.resize(self.remainder.len() * 2, 0);
+            }
+            let carried = self.remainder.len();
+            data[..carried].copy_from_slice(&self.remainder);
+            self.remainder.clear();
+            let start_reading = Instant::now();
+            let read = fill(&mut reader, &mut data[carried..])?;
+            self.timings.reading += start_reading.elapsed();
+            let len = carried + read;
+            let split = memchr::memrchr(b'\n', &data[..len]).map_or(0, |i| i + 1);
+            if split == 0 && len == data.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Line longer than the read buffer",
+                )
+                .into());
+            }
+            self.remainder.extend_from_slice(&data[split..len]);
+            let buffer = Buffer {
+                data,
+                len: split,
+                recycle: free_sender.clone(),
+            };
+            self.submit(Arc::new(buffer), 0..split);
+            self.collect(false)?;
+            if read == 0 {
+                break;
+            }
+        }
+        self.wait()
+    }
+
+    /// Memory-maps `file` and splits all of it across the threads at once,
+    /// avoiding the copies into the read buffers.
+    pub fn read_mmap(&mut self, file: &File) -> Result<(), Error> {
+        // SAFETY: the map is only read while it is alive, and the file is not
+        // expected to be modified concurrently.
+        let mmap = unsafe { memmap2::Mmap::map(file)? };
+        self.submit_shared(Arc::new(mmap))?;
+        self.wait()
+    }
+
+    /// Ends the current input: an unterminated last line is aggregated as
+    /// is, and offsets and line numbers start over for the next input.
+    pub fn end_of_input(&mut self) -> Result<(), Error> {
+        if !self.remainder.is_empty() {
+            let mut last_line = std::mem::take(&mut self.remainder);
+            last_line.push(b'\n');
+            let len = last_line.len();
+            self.submit(Arc::new(last_line), 0..len);
+        }
+        self.wait()?;
+        self.input += 1;
+        self.offset = 0;
+        self.lines = 0;
+        Ok(())
+    }
+
+    /// Ends the input and returns the results sorted by station name bytes.
+    pub fn finish(mut self) -> Result<Results, Error> {
+        self.end_of_input()?;
+        let mut invalid_names = BTreeSet::new();
+        let mut registry = self
+            .pool
+            .take()
+            .map(Pool::join)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|state| {
+                invalid_names.extend(state.invalid_names);
+                state.registry
+            })
+            .reduce(|mut a, b| {
+                for (name, aggregation) in b {
+                    merge_into(&mut a, &name, aggregation);
+                }
+                a
+            })
+            .unwrap_or_default();
+        if self.normalize_names {
+            registry = normalize_names(registry);
+        }
+        if let Some(separator) = self.rollup {
+            roll_up(&mut registry, separator);
+        }
+        let mut name_aggregations = registry.into_iter().collect::<Vec<_>>();
+        name_aggregations.sort_unstable_by(|(name1, _), (name2, _)| name1.cmp(name2));
+        Ok(Results {
+            name_aggregations,
+            rejected_lines: self.rejected_lines,
+            filtered_lines: self.filtered_lines,
+            errors: std::mem::take(&mut self.errors),
+            invalid_names: invalid_names.into_iter().collect(),
+        })
+    }
+
+    /// Submits the complete lines in `data`, stitching the first one onto the
+    /// current remainder and keeping the trailing partial line as the new one.
+    fn submit_shared(&mut self, data: Bytes) -> Result<(), Error> {
+        let bytes = (*data).as_ref();
+        let Some(last_newline) = memchr::memrchr(b'\n', bytes) else {
+            self.remainder.extend_from_slice(bytes);
+            return Ok(());
+        };
+        let mut start = 0;
+        if !self.remainder.is_empty() {
+            let first_newline = memchr::memchr(b'\n', bytes).expect("There is a newline");
+            let mut stitched = std::mem::take(&mut self.remainder);
+            stitched.extend_from_slice(&bytes[..=first_newline]);
+            let len = stitched.len();
+            self.submit(Arc::new(stitched), 0..len);
+            start = first_newline + 1;
+        }
+        self.remainder.extend_from_slice(&bytes[last_newline + 1..]);
+        self.submit(Arc::clone(&data), start..last_newline + 1);
+        self.collect(false)
+    }
+
+    /// Hands the newline-terminated lines in `data[range]` to the workers,
+    /// which claim them in [`CHUNKS_PER_THREAD`] chunks each, of between
+    /// [`MIN_CHUNK_SIZE`] and [`MAX_CHUNK_SIZE`].
+    fn submit(&mut self, data: Bytes, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let (threads, settings) = (self.threads, &self.settings);
+        let pool = self
+            .pool
+            .get_or_insert_with(|| Pool::new(threads, Arc::new(settings.clone())));
+        let len = range.len();
+        let batch = Batch {
+            seq: self.submitted,
+            offset: self.offset,
+            data,
+            range,
+            chunk_size: len
+                .div_ceil(pool.threads() * CHUNKS_PER_THREAD)
+                .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE),
+            next_chunk: AtomicUsize::new(0),
+        };
+        self.submitted += batch.chunks() as u64;
+        self.offset += len as u64;
+        pool.submit(batch);
+    }
+
+    /// Waits until every submitted chunk has been accounted for.
+    fn wait(&mut self) -> Result<(), Error> {
+        self.collect(true)
+    }
+
+    /// Accounts for finished chunks in submission order, which is what gives
+    /// errors their line numbers. Only blocks if `block` is set.
+    fn collect(&mut self, block: bool) -> Result<(), Error> {
+        let Some(pool) = &self.pool else {
+            return Ok(());
+        };
+        while self.accounted < self.submitted {
+            let received = if block {
+                Some(pool.outcomes().recv().expect("Worker thread panicked"))
+            } else {
+                pool.outcomes().try_recv().ok()
+            };
+            let Some((seq, outcome)) = received else {
+                break;
+            };
+            self.pending.insert(seq, outcome);
+            while let Some(outcome) = self.pending.remove(&self.accounted) {
+                self.accounted += 1;
+                for mut error in outcome.errors {
+                    error.input = self.input;
+                    error.line += self.lines;
+                    if self.settings.on_error == OnError::Abort {
+                        return Err(error.into());
+                    }
+                    self.errors.push(error);
+                }
+                self.rejected_lines += outcome.rejected_lines;
+                self.filtered_lines += outcome.filtered_lines;
+                self.lines += outcome.lines;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads until `buffer` is full or the reader is exhausted.
+fn fill(reader: &mut (impl Read + ?Sized), buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Merges every name into each of its prefixes that ends before `separator`.
+fn roll_up(registry: &mut Registry, separator: u8) {
+    let mut groups = Registry::default();
+    for (name, aggregation) in registry.iter() {
+        for end in memchr::memchr_iter(separator, name) {
+            match groups.get_mut(&name[..end]) {
+                Some(group) => group.merge(aggregation),
+                None => {
+                    groups.insert(&name[..end], aggregation.clone());
+                }
+            }
+        }
+    }
+    for (prefix, group) in groups {
+        merge_into(registry, &prefix, group);
+    }
+}
+
+/// Re-keys `registry` by the NFC form of the names, merging the ones that
+/// coincide.
+fn normalize_names(registry: Registry) -> Registry {
+    let mut normalized = Registry::default();
+    for (name, aggregation) in registry {
+        let name = match std::str::from_utf8(&name) {
+            Ok(text) if !unicode_normalization::is_nfc(text) => {
+                text.nfc().collect::<String>().into_bytes()
+            }
+            _ => name,
+        };
+        merge_into(&mut normalized, &name, aggregation);
+    }
+    normalized
+}
+
+fn merge_into(registry: &mut Registry, name: &[u8], aggregation: Aggregation) {
+    match registry.get_mut(name) {
+        Some(existing) => existing.merge(&aggregation),
+        None => {
+            registry.insert(name, aggregation);
+        }
+    }
+}
+
+/// Per-chunk line counts and errors, with line numbers relative to the start
+/// of the chunk.
+#[derive(Default)]
+struct ChunkOutcome {
+    lines: u64,
+    rejected_lines: u64,
+    filtered_lines: u64,
+    errors: Vec<ParseError>,
+}
+
+/// What became of a line.
+enum LineOutcome {
+    Aggregated,
+    Filtered,
+    Invalid(ParseErrorKind),
+}
+
+fn process_chunk(
+    state: &mut WorkerState,
+    chunk: &[u8],
+    offset: u64,
+    settings: &Settings,
+) -> ChunkOutcome {
+    let mut outcome = ChunkOutcome::default();
+    let mut key = Vec::new();
+    let mut start = 0;
+    for end in memchr::memchr_iter(b'\n', chunk) {
+        let line = &chunk[start..end];
+        outcome.lines += 1;
+        let registry = &mut state.registry;
+        match process_line(
+            registry,
+            &mut state.filtered_names,
+            &mut state.invalid_names,
+            line,
+            settings,
+            &mut key,
+        ) {
+            LineOutcome::Aggregated => {}
+            LineOutcome::Filtered => outcome.filtered_lines += 1,
+            LineOutcome::Invalid(kind) => {
+                outcome.rejected_lines += 1;
+                if settings.on_error != OnError::Skip {
+                    outcome.errors.push(ParseError {
+                        kind,
+                        input: 0,
+                        offset: offset + start as u64,
+                        line: outcome.lines,
+                        bytes: line.to_vec(),
+                    });
+                    if settings.on_error == OnError::Abort {
+                        break;
+                    }
+                }
+            }
+        }
+        start = end + 1;
+    }
+    outcome
+}
+
+/// Only names that passed the filter make it into the registry, so it only
+/// needs to look at names seen for the first time, and so does the UTF-8
+/// validation.
+fn process_line(
+    registry: &mut Registry,
+    filtered_names: &mut FxHashSet<Vec<u8>>,
+    invalid_names: &mut FxHashSet<Vec<u8>>,
+    line: &[u8],
+    settings: &Settings,
+    key: &mut Vec<u8>,
+) -> LineOutcome {
+    let Some((name, temp)) = parse_line(line, settings.scale, &settings.layout, key) else {
+        return LineOutcome::Invalid(ParseErrorKind::Format);
+    };
+    if !settings.filter.accepts_temperature(temp) {
+        return LineOutcome::Filtered;
+    }
+    match registry.get_mut(name) {
+        Some(aggregation) => aggregation.update(temp),
+        None => {
+            if settings.filter.filters_names() {
+                if filtered_names.contains(name) {
+                    return LineOutcome::Filtered;
+                }
+                if !settings.filter.accepts_name(name) {
+                    filtered_names.insert(name.to_vec());
+                    return LineOutcome::Filtered;
+                }
+            }
+            if let Some(validation) = settings.validate_utf8 {
+                // Only rejected names come back here.
+                if invalid_names.contains(name) {
+                    return LineOutcome::Invalid(ParseErrorKind::InvalidUtf8);
+                }
+                if std::str::from_utf8(name).is_err() {
+                    invalid_names.insert(name.to_vec());
+                    if validation == Utf8Validation::Reject {
+                        return LineOutcome::Invalid(ParseErrorKind::InvalidUtf8);
+                    }
+                }
+            }
+            let mut aggregation = Aggregation::new(settings.scale, settings.histograms);
+            aggregation.update(temp);
+            registry.insert(name, aggregation);
+        }
+    }
+    LineOutcome::Aggregated
+}
@@ -1,222 +1,431 @@
 use std::{
-    array,
     fs::File,
-    io::{BufWriter, Read, Write},
-    path::PathBuf,
-    thread,
+    io::{BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
 };
 
+use anyhow::Context;
 use clap::Parser;
-use fxhash::FxHashMap;
-use itertools::Itertools;
-
-const PARALLELISM: usize = 8;
-const BUFFER_SIZE: usize = 128 * 1024 * 1024;
+use humansize::{format_size, BINARY};
+use one_billion_row_challenge::{
+    decompress, escape_name, write_results, Aggregator, Collation, Column, Compression, Format,
+    OnError, OutputOptions, Rounding, SortBy, SortOptions, Utf8Validation, DEFAULT_BUFFER_SIZE,
+    HISTOGRAM_SIZE, MAX_SCALE,
+};
 
 fn main() -> anyhow::Result<()> {
     let start = std::time::Instant::now();
     let args = Args::parse();
-    let mut file = File::open(&args.input_file)?;
+    let inputs = expand_inputs(&args)?;
+    if args.histogram.is_some() && args.format == Format::Text {
+        anyhow::bail!("--histogram needs a --format other than text");
+    }
+    let bucket_width = args
+        .histogram
+        .map(|width| bucket_width(width, args.scale))
+        .transpose()?;
     eprintln!("Setup took {:?}", start.elapsed());
     let start_parsing = std::time::Instant::now();
-    let mut working_buffer = vec![0_u8; BUFFER_SIZE];
-    let mut loading_buffer = vec![0_u8; BUFFER_SIZE];
-    let mut registries: [Registry; PARALLELISM] = array::from_fn(|_| Registry::default());
-    file.read(&mut working_buffer)?;
-    loop {
-        let (remainder, to_process) = working_buffer
-            .rsplitn(2, |&b| b == b'\n')
-            .collect_tuple()
-            .ok_or_else(|| anyhow::anyhow!("No newline found in working buffer"))?;
-        let chunks = chunk_at_newlines(to_process);
-        let read = thread::scope(|s| {
-            chunks
-                .iter()
-                .zip(registries.iter_mut())
-                .for_each(|(chunk, mut registry)| {
-                    s.spawn(move || {
-                        let mut start = 0;
-                        for end in memchr::memchr_iter(b'\n', chunk).chain([chunk.len()]) {
-                            process_line(&mut registry, &chunk[start..end]);
-                            start = end + 1;
-                        }
-                    });
-                });
-            loading_buffer[..remainder.len()].copy_from_slice(remainder);
-            file.read(&mut loading_buffer[remainder.len()..])
-        })?;
-        if read == 0 {
-            break;
+    let mut buffer_size = args.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    if let Some(max_memory) = args.max_memory {
+        // Leave at least half the budget to the registries.
+        let budget_buffer_size = max_memory / 4;
+        if args
+            .buffer_size
+            .is_some_and(|size| size > budget_buffer_size)
+        {
+            eprintln!(
+                "Warning: two {} buffers exceed half of the {} memory budget",
+                format_size(buffer_size, BINARY),
+                format_size(max_memory, BINARY)
+            );
+        } else if args.buffer_size.is_none() {
+            buffer_size = buffer_size.min(budget_buffer_size);
         }
-        if read + remainder.len() < loading_buffer.len() {
-            loading_buffer.drain((read + remainder.len())..);
-        }
-        std::mem::swap(&mut working_buffer, &mut loading_buffer);
-    }
-    let registry = registries
-        .into_iter()
-        .reduce(|mut a, b| {
-            for (name, aggregation) in b {
-                match a.get_mut(&name) {
-                    Some(existing) => existing.merge(&aggregation),
-                    None => {
-                        a.insert(name, aggregation);
-                    }
-                }
+    }
+    let histograms = !args.percentiles.is_empty() || args.histogram.is_some();
+    let mut aggregator = Aggregator::new()
+        .on_error(args.on_error)
+        .buffer_size(buffer_size)
+        .scale(args.scale)
+        .delimiter(args.delimiter)
+        .histograms(histograms);
+    if let Some(threads) = args.threads {
+        aggregator = aggregator.threads(threads);
+    }
+    if let (Some(max_memory), true) = (args.max_memory, histograms) {
+        let station_size = HISTOGRAM_SIZE * aggregator.thread_count();
+        eprintln!(
+            "Warning: histograms take {} per station and thread, so half of the {} memory budget \
+             fits about {} stations on {} threads",
+            format_size(HISTOGRAM_SIZE, BINARY),
+            format_size(max_memory, BINARY),
+            max_memory / 2 / station_size,
+            aggregator.thread_count()
+        );
+    }
+    if let Some(validation) = args.validate_utf8 {
+        aggregator = aggregator.validate_utf8(validation);
+    }
+    if !args.key_column.is_empty() || args.value_column.is_some() {
+        let key_columns = match &args.key_column[..] {
+            [] => &[0][..],
+            key_columns => key_columns,
+        };
+        if let Some(value_column) = args.value_column {
+            if key_columns.contains(&value_column) {
+                anyhow::bail!("The key and value columns must differ");
             }
-            a
-        })
-        .expect("At least one registry");
+        }
+        aggregator = aggregator.columns(key_columns, args.value_column);
+    }
+    if let Some(separator) = args.rollup {
+        aggregator = aggregator.rollup(separator);
+    }
+    if args.nfc {
+        aggregator = aggregator.normalize_names(true);
+    }
+    if let Some(pattern) = &args.station_regex {
+        aggregator = aggregator.station_regex(regex::bytes::Regex::new(pattern)?);
+    }
+    if let Some(path) = &args.station_list {
+        let list =
+            std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let stations = list
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.is_empty())
+            .map(<[u8]>::to_vec);
+        aggregator = aggregator.stations(stations);
+    }
+    if let Some(min) = args.min_temp {
+        aggregator = aggregator.min_temperature(temperature(min, args.scale)?);
+    }
+    if let Some(max) = args.max_temp {
+        aggregator = aggregator.max_temperature(temperature(max, args.scale)?);
+    }
+    for input in &inputs {
+        aggregate_input(&mut aggregator, input, args.io)
+            .with_context(|| format!("Failed to aggregate {}", input.display()))?;
+    }
     let elapsed = start_parsing.elapsed();
     eprintln!("Aggregation took {:?}", elapsed);
+    let timings = aggregator.timings();
+    if let IoMode::Read = args.io {
+        eprintln!(
+            "  of which reading took {:?} and waiting for free buffers took {:?}",
+            timings.reading, timings.waiting
+        );
+    }
+    eprintln!("  worker busy times: {:?}", timings.busy);
+    if let Some(max_memory) = args.max_memory {
+        let buffers_memory = match args.io {
+            IoMode::Read => 2 * buffer_size,
+            IoMode::Mmap => 0,
+        };
+        let registries_memory = aggregator.registries_memory();
+        if buffers_memory + registries_memory > max_memory {
+            eprintln!(
+                "Warning: estimated memory use of {} ({} registries) exceeds the {} budget",
+                format_size(buffers_memory + registries_memory, BINARY),
+                format_size(registries_memory, BINARY),
+                format_size(max_memory, BINARY)
+            );
+        }
+    }
 
     let start_sorting = std::time::Instant::now();
-    let mut name_aggregations = registry.into_iter().collect::<Vec<_>>();
-    name_aggregations.sort_unstable_by(|(name1, _), (name2, _)| name1.cmp(name2));
+    let mut results = aggregator.finish()?;
+    results.sort(&SortOptions {
+        sort_by: args.sort_by,
+        descending: args.desc,
+        top: args.top,
+        collation: args.collation,
+    });
     let elapsed = start_sorting.elapsed();
     eprintln!("Sorting took {:?}", elapsed);
 
-    let handle = std::io::stdout().lock();
+    let handle: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout().lock()),
+    };
     let mut writer = BufWriter::new(handle);
 
     let start_writing = std::time::Instant::now();
-    writer.write_all(b"{")?;
-    let (first_name, first_aggregation) = name_aggregations.first().unwrap();
-    push_aggregation(&mut writer, first_name, first_aggregation)?;
-    for (name, aggregation) in &name_aggregations[1..] {
-        writer.write_all(b", ")?;
-        push_aggregation(&mut writer, name, aggregation)?;
-    }
-    writer.write_all(b"}")?;
+    let output_options = OutputOptions {
+        format: args.format,
+        columns: args.columns,
+        percentiles: args.percentiles,
+        histogram: bucket_width,
+        rounding: args.rounding,
+        escape_names: args.validate_utf8.is_some(),
+    };
+    write_results(&mut writer, &results.name_aggregations, &output_options)?;
+    writer.flush()?;
     let elapsed = start_writing.elapsed();
     eprintln!("Writing took {:?}", elapsed);
 
+    if results.filtered_lines > 0 {
+        eprintln!("Filtered out {} lines", results.filtered_lines);
+    }
+    if !results.invalid_names.is_empty() {
+        eprintln!(
+            "Found {} station names that are not valid UTF-8",
+            results.invalid_names.len()
+        );
+        for name in &results.invalid_names {
+            eprintln!("  {}", String::from_utf8_lossy(&escape_name(name)));
+        }
+    }
+    if results.rejected_lines > 0 {
+        eprintln!("Rejected {} invalid lines", results.rejected_lines);
+        for error in &results.errors {
+            eprintln!("  {}: {error}", inputs[error.input].display());
+        }
+    }
+
     Ok(())
 }
 
-fn chunk_at_newlines(to_chunk: &[u8]) -> [&[u8]; PARALLELISM] {
-    let chunk_size = to_chunk.len() / PARALLELISM;
-    let mut start = 0;
-    array::from_fn(|i| {
-        let end = if i == PARALLELISM - 1 {
-            to_chunk.len()
-        } else {
-            memchr::memrchr(b'\n', &to_chunk[..(start + chunk_size)])
-                .expect("There should always be a newline")
-        };
-        let ret = &to_chunk[start..end];
-        start = end + 1;
-        ret
-    })
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Measurements files to aggregate together, `-` for stdin; glob patterns are expanded
+    #[arg(required_unless_present = "input_file")]
+    inputs: Vec<String>,
+
+    /// Path to measurements file, may be repeated
+    #[arg(short, long)]
+    input_file: Vec<PathBuf>,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Write the results to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Columns to output [default: name,min,mean,max for text, all but variance for the others]
+    #[arg(long, value_enum, value_delimiter = ',')]
+    columns: Option<Vec<Column>>,
+
+    /// Order of the stations
+    #[arg(long, value_enum, default_value_t = SortBy::Name)]
+    sort_by: SortBy,
+
+    /// Sort in descending order
+    #[arg(long)]
+    desc: bool,
+
+    /// Only output the first N stations in that order, e.g. --sort-by mean --desc --top 20 for the hottest
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Order of the names, when sorting by name and to break ties
+    #[arg(long, value_enum, default_value_t = Collation::Byte)]
+    collation: Collation,
+
+    /// Merge names that are the same in Unicode NFC, e.g. composed and decomposed Zürich
+    #[arg(long)]
+    nfc: bool,
+
+    /// Percentiles to output after the columns, e.g. 50,90,99; keeps a 16KiB histogram per station and thread
+    #[arg(long, value_delimiter = ',', value_parser = parse_percentile)]
+    percentiles: Vec<f64>,
+
+    /// Output per-station histograms with buckets this many degrees wide, e.g. 0.5, instead of the columns
+    #[arg(long, value_name = "BUCKET_WIDTH")]
+    histogram: Option<f64>,
+
+    /// Byte between the columns of a line, e.g. , or \t
+    #[arg(long, default_value = ";", value_parser = parse_delimiter)]
+    delimiter: u8,
+
+    /// Zero-based columns of the station name, joined with the delimiter when several, allowing extra columns [default: 0]
+    #[arg(long, value_delimiter = ',')]
+    key_column: Vec<usize>,
+
+    /// Zero-based column of the temperature, allowing extra columns [default: the last]
+    #[arg(long)]
+    value_column: Option<usize>,
+
+    /// Also aggregate every prefix of the names up to this byte, e.g. the country of country;station keys
+    #[arg(long, value_name = "SEPARATOR", value_parser = parse_separator)]
+    rollup: Option<u8>,
+
+    /// Only aggregate stations whose name matches this regular expression
+    #[arg(long, value_name = "REGEX")]
+    station_regex: Option<String>,
+
+    /// Only aggregate the stations listed in this file, one per line
+    #[arg(long, value_name = "FILE")]
+    station_list: Option<PathBuf>,
+
+    /// Only aggregate temperatures of at least this many degrees
+    #[arg(long, allow_negative_numbers = true)]
+    min_temp: Option<f64>,
+
+    /// Only aggregate temperatures of at most this many degrees
+    #[arg(long, allow_negative_numbers = true)]
+    max_temp: Option<f64>,
+
+    /// Number of decimal places of the temperatures; more are rejected, fewer are padded
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=MAX_SCALE as i64))]
+    scale: u8,
+
+    /// How to round the mean to the scale when exactly halfway
+    #[arg(long, value_enum, default_value_t = Rounding::HalfUp)]
+    rounding: Rounding,
+
+    /// Number of worker threads [default: available parallelism]
+    #[arg(short, long)]
+    threads: Option<usize>,
+
+    /// How to handle lines that fail to parse
+    #[arg(long, value_enum, default_value_t = OnError::Abort)]
+    on_error: OnError,
+
+    /// Check that station names are valid UTF-8, reporting or rejecting the others, and escape names in the output as \xNN
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "report"
+    )]
+    validate_utf8: Option<Utf8Validation>,
+
+    /// How to load input files (stdin and compressed files are always read)
+    #[arg(long, value_enum, default_value_t = IoMode::Read)]
+    io: IoMode,
+
+    /// Size of each of the two read buffers, e.g. 64MiB [default: 128MiB]
+    #[arg(long, value_parser = parse_size)]
+    buffer_size: Option<usize>,
+
+    /// Memory budget, e.g. 1GiB; shrinks the default buffers to fit and warns when exceeded
+    #[arg(long, value_parser = parse_size)]
+    max_memory: Option<usize>,
 }
 
-fn process_line(registry: &mut Registry, line: &[u8]) {
-    let (name, temp) = parse_line(line);
-    match registry.get_mut(name) {
-        Some(aggregation) => aggregation.update(temp),
-        None => {
-            let mut aggregation = Aggregation::new();
-            aggregation.update(temp);
-            registry.insert(name.to_vec(), aggregation);
+fn expand_inputs(args: &Args) -> anyhow::Result<Vec<PathBuf>> {
+    let mut inputs = args.input_file.clone();
+    for input in &args.inputs {
+        let is_pattern = input.contains(['*', '?', '[']);
+        if input == "-" || !is_pattern || Path::new(input).exists() {
+            inputs.push(PathBuf::from(input));
+            continue;
+        }
+        let matches = glob::glob(input)?.collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            anyhow::bail!("No files match {input:?}");
         }
+        inputs.extend(matches);
     }
+    Ok(inputs)
 }
 
-fn parse_line(line: &[u8]) -> (&[u8], i32) {
-    let (name, is_negative, tens, ones, decimal) = match line {
-        [name @ .., b';', b'-', tens, ones, b'.', decimal] => (name, true, *tens, *ones, *decimal),
-        [name @ .., b';', b'-', ones, b'.', decimal] => (name, true, b'0', *ones, *decimal),
-        [name @ .., b';', tens, ones, b'.', decimal] => (name, false, *tens, *ones, *decimal),
-        [name @ .., b';', ones, b'.', decimal] => (name, false, b'0', *ones, *decimal),
-        _ => panic!("Invalid line format {}", String::from_utf8_lossy(line)),
-    };
-    let zero = b'0' as i32;
-    let value =
-        ((tens as i32 - zero) * 100) + ((ones as i32 - zero) * 10) + (decimal as i32 - zero);
-    if is_negative {
-        (name, -value)
+fn aggregate_input(aggregator: &mut Aggregator, input: &Path, io: IoMode) -> anyhow::Result<()> {
+    if input == Path::new("-") {
+        aggregator.read_from(decompress(std::io::stdin())?)?;
     } else {
-        (name, value)
+        let mut file = File::open(input)?;
+        let mut magic = Vec::new();
+        (&file).take(4).read_to_end(&mut magic)?;
+        file.rewind()?;
+        match io {
+            IoMode::Mmap if Compression::detect(&magic).is_none() => aggregator.read_mmap(&file)?,
+            _ => aggregator.read_from(decompress(file)?)?,
+        }
     }
+    aggregator.end_of_input()?;
+    Ok(())
 }
 
-fn push_aggregation(
-    writer: &mut impl Write,
-    name: &[u8],
-    aggregation: &Aggregation,
-) -> anyhow::Result<()> {
-    writer.write_all(name)?;
-    writer.write_all(b"=")?;
-    push_float(writer, aggregation.min)?;
-    writer.write_all(b"/")?;
-    push_float(writer, aggregation.mean())?;
-    writer.write_all(b"/")?;
-    push_float(writer, aggregation.max)?;
-    Ok(())
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: usize = number.parse().map_err(|_| format!("Invalid size {s:?}"))?;
+    let multiplier: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err(format!("Unknown size unit in {s:?}")),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size {s:?} is too large"))
 }
 
-fn push_float(writer: &mut impl Write, mut value: i32) -> anyhow::Result<()> {
-    if value < 0 {
-        writer.write_all(b"-")?;
-        value = -value;
-    }
-    if value >= 100 {
-        writer.write_all(&[(value / 100) as u8 + b'0'])?;
+fn parse_percentile(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(percentile) if (0.0..=100.0).contains(&percentile) => Ok(percentile),
+        _ => Err(format!(
+            "Invalid percentile {s:?}, expected a number from 0 to 100"
+        )),
     }
-    writer.write_all(&[((value / 10) % 10) as u8 + b'0'])?;
-    writer.write_all(b".")?;
-    writer.write_all(&[(value % 10) as u8 + b'0'])?;
-    Ok(())
 }
 
-type Registry = FxHashMap<Vec<u8>, Aggregation>;
-
-struct Aggregation {
-    min: i32,
-    max: i32,
-    sum: i32,
-    count: u32,
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match parse_separator(s)? {
+        b'\n' | b'\r' | b'-' | b'.' | b'0'..=b'9' => Err(format!(
+            "Invalid delimiter {s:?}, expected a byte that cannot be part of a number"
+        )),
+        delimiter => Ok(delimiter),
+    }
 }
 
-impl Aggregation {
-    fn new() -> Self {
-        Self {
-            min: i32::MAX,
-            max: i32::MIN,
-            sum: 0,
-            count: 0,
-        }
+fn parse_separator(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" | "tab" => Ok(b'\t'),
+        _ => match s.as_bytes() {
+            [separator] => Ok(*separator),
+            _ => Err(format!("Invalid separator {s:?}, expected a single byte")),
+        },
     }
+}
 
-    fn update(&mut self, value: i32) {
-        self.min = self.min.min(value);
-        self.max = self.max.max(value);
-        self.sum += value;
-        self.count += 1;
+/// Converts a width in degrees into fixed-point units with `scale` decimal
+/// places.
+fn bucket_width(degrees: f64, scale: u8) -> anyhow::Result<u32> {
+    match fixed_point(degrees, scale) {
+        Some(units) if units >= 1 && units <= u32::MAX as i64 => Ok(units as u32),
+        _ => anyhow::bail!(
+            "Invalid bucket width {degrees}, expected a positive multiple of {} degrees",
+            10_f64.powi(-(scale as i32))
+        ),
     }
+}
 
-    fn merge(&mut self, other: &Self) {
-        self.min = self.min.min(other.min);
-        self.max = self.max.max(other.max);
-        self.sum += other.sum;
-        self.count += other.count;
+/// Converts a temperature in degrees into fixed-point units with `scale`
+/// decimal places.
+fn temperature(degrees: f64, scale: u8) -> anyhow::Result<i32> {
+    match fixed_point(degrees, scale).map(i32::try_from) {
+        Some(Ok(units)) => Ok(units),
+        _ => anyhow::bail!(
+            "Invalid temperature {degrees}, expected a multiple of {} degrees",
+            10_f64.powi(-(scale as i32))
+        ),
     }
+}
 
-    fn mean(&self) -> i32 {
-        let mean_10 = self.sum * 10 / self.count as i32;
-        let remainder = mean_10 % 10;
-        if remainder >= 5 {
-            mean_10 / 10 + 1
-        } else {
-            mean_10 / 10
-        }
-    }
+/// `degrees` in units of `10^-scale` degrees, if it is a whole number of them.
+fn fixed_point(degrees: f64, scale: u8) -> Option<i64> {
+    let units = degrees * 10_f64.powi(scale as i32);
+    let rounded = units.round();
+    ((units - rounded).abs() < 1e-6 && rounded.abs() < i64::MAX as f64).then_some(rounded as i64)
 }
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Path to measurements file
-    #[arg(short, long)]
-    input_file: PathBuf,
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum IoMode {
+    /// Read into a pair of buffers, loading one while processing the other
+    Read,
+    /// Memory-map the whole file and process it in one go
+    Mmap,
 }
@@ -0,0 +1,356 @@
+use std::vec;
+
+/// Names up to this long are stored in the table itself, which at 32 bytes
+/// with the length and variant costs nothing next to an `Aggregation`.
+const INLINE_LEN: usize = 30;
+const MIN_SLOTS: usize = 16;
+
+/// Open-addressing hash table keyed by station names.
+///
+/// Made for the registries, which look up a few thousand short names once per
+/// line. The entries are kept densely in insertion order, with names of up to
+/// 30 bytes inline next to their value. The slots only hold the entry's index
+/// and part of the hash, 4 bytes each, so linear probing stays within a small
+/// array and only touches an entry once the hashes match. At most a quarter of
+/// the slots are taken, which keeps the probes short and predictable.
+#[doc(hidden)]
+pub struct NameMap<V> {
+    /// The index of the entry plus one in the low `log2(slots.len())` bits,
+    /// under [`NameMap::tag`], or 0 for an empty slot.
+    slots: Vec<u32>,
+    entries: Vec<(Name, V)>,
+    /// `64 - log2(slots.len())`, to index the slots with the top bits of the
+    /// hash.
+    shift: u32,
+}
+
+enum Name {
+    Inline { len: u8, bytes: [u8; INLINE_LEN] },
+    Boxed(Box<[u8]>),
+}
+
+impl Name {
+    fn new(name: &[u8]) -> Self {
+        if name.len() > INLINE_LEN {
+            return Name::Boxed(name.into());
+        }
+        let mut bytes = [0; INLINE_LEN];
+        bytes[..name.len()].copy_from_slice(name);
+        Name::Inline {
+            len: name.len() as u8,
+            bytes,
+        }
+    }
+
+    /// Whether this is `name`, comparing the same words as [`hash`].
+    fn matches(&self, name: &[u8]) -> bool {
+        let bytes = self.as_bytes();
+        let len = bytes.len();
+        if len != name.len() {
+            return false;
+        }
+        let same = |start| word(bytes, start) == word(name, start);
+        match len {
+            0..=8 => prefix(bytes) == prefix(name),
+            9..=16 => same(0) && same(len - 8),
+            17..=32 => same(0) && same(8) && same(len - 16) && same(len - 8),
+            _ => bytes == name,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Name::Inline { len, bytes } => &bytes[..*len as usize],
+            Name::Boxed(bytes) => bytes,
+        }
+    }
+}
+
+/// The first 8 bytes of `name` as a word, zero-padded.
+fn prefix(name: &[u8]) -> u64 {
+    if let Some(first) = name.first_chunk::<8>() {
+        return u64::from_le_bytes(*first);
+    }
+    let mut word = [0; 8];
+    word[..name.len()].copy_from_slice(name);
+    u64::from_le_bytes(word)
+}
+
+/// The 8 bytes of `name` from `start` as a word.
+fn word(name: &[u8], start: usize) -> u64 {
+    u64::from_le_bytes(name[start..start + 8].try_into().expect("8 bytes"))
+}
+
+/// FxHash-style mixing of the length and every byte of the name, read as
+/// possibly overlapping words: one under 8 bytes, two up to 16 and four up to
+/// 32, so that names differing only in the middle don't collide. Good in the
+/// top bits.
+fn hash(name: &[u8]) -> u64 {
+    const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+    let add = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    let len = name.len();
+    let hash = add(len as u64, prefix(name));
+    match len {
+        0..=8 => hash,
+        9..=16 => add(hash, word(name, len - 8)),
+        17..=32 => {
+            let hash = add(add(hash, word(name, 8)), word(name, len - 16));
+            add(hash, word(name, len - 8))
+        }
+        _ => {
+            let hash = name[8..len - 8]
+                .chunks(8)
+                .fold(hash, |hash, chunk| add(hash, prefix(chunk)));
+            add(hash, word(name, len - 8))
+        }
+    }
+}
+
+impl<V> NameMap<V> {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get_mut(&mut self, name: &[u8]) -> Option<&mut V> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let index = self.find(name, hash(name)).ok()?;
+        Some(&mut self.entries[index].1)
+    }
+
+    /// Inserts `value` under `name`, replacing any previous one.
+    pub fn insert(&mut self, name: &[u8], value: V) {
+        if (self.entries.len() + 1) * 4 > self.slots.len() {
+            self.grow();
+        }
+        let hash = hash(name);
+        match self.find(name, hash) {
+            Ok(index) => self.entries[index].1 = value,
+            Err(slot) => {
+                self.slots[slot] = self.tag(hash) | (self.entries.len() as u32 + 1);
+                self.entries.push((Name::new(name), value));
+            }
+        }
+    }
+
+    /// The entries in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &V)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_bytes(), value))
+    }
+
+    /// Bytes held by the slots, the entries and the names too long to be
+    /// inline, but not by whatever the values point to.
+    pub fn table_size(&self) -> usize {
+        let boxed: usize = self
+            .entries
+            .iter()
+            .map(|(name, _)| match name {
+                Name::Inline { .. } => 0,
+                Name::Boxed(bytes) => bytes.len(),
+            })
+            .sum();
+        self.slots.capacity() * size_of::<u32>()
+            + self.entries.capacity() * size_of::<(Name, V)>()
+            + boxed
+    }
+
+    /// The index of the entry for `name` if any, or else the empty slot it
+    /// would go in. There must be at least one empty slot.
+    fn find(&self, name: &[u8], hash: u64) -> Result<usize, usize> {
+        let mask = self.slots.len() - 1;
+        let tag = self.tag(hash);
+        let index_mask = mask as u32;
+        let mut slot = (hash >> self.shift) as usize;
+        loop {
+            match self.slots[slot] {
+                0 => return Err(slot),
+                taken if taken & !index_mask == tag => {
+                    let index = ((taken & index_mask) - 1) as usize;
+                    if self.entries[index].0.matches(name) {
+                        return Ok(index);
+                    }
+                }
+                _ => {}
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    /// As many bits of the hash as the slots have room for above the entry
+    /// index, which takes `log2(slots.len())` bits.
+    fn tag(&self, hash: u64) -> u32 {
+        let index_bits = 64 - self.shift;
+        ((hash >> 16) as u32 >> index_bits) << index_bits
+    }
+
+    fn grow(&mut self) {
+        let slots = (self.slots.len() * 2).max(MIN_SLOTS);
+        assert!(slots <= 1 << 31, "Too many names");
+        self.slots = vec![0; slots];
+        self.shift = 64 - slots.trailing_zeros();
+        for (index, (name, _)) in self.entries.iter().enumerate() {
+            let hash = hash(name.as_bytes());
+            let mut slot = (hash >> self.shift) as usize;
+            while self.slots[slot] != 0 {
+                slot = (slot + 1) & (slots - 1);
+            }
+            self.slots[slot] = self.tag(hash) | (index as u32 + 1);
+        }
+    }
+}
+
+impl<V> Default for NameMap<V> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            entries: Vec::new(),
+            shift: 64,
+        }
+    }
+}
+
+/// The entries of a [`NameMap`] in insertion order, with their names.
+#[doc(hidden)]
+pub struct IntoIter<V>(vec::IntoIter<(Name, V)>);
+
+impl<V> Iterator for IntoIter<V> {
+    type Item = (Vec<u8>, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (name, value) = self.0.next()?;
+        let name = match name {
+            Name::Inline { .. } => name.as_bytes().to_vec(),
+            Name::Boxed(bytes) => bytes.into_vec(),
+        };
+        Some((name, value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<V> IntoIterator for NameMap<V> {
+    type Item = (Vec<u8>, V);
+    type IntoIter = IntoIter<V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter(self.entries.into_iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| format!("Station-{i:05}-observatory").into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn shared_ends_hash_apart() {
+        let mut hashes = names(5000)
+            .iter()
+            .map(|name| hash(name) >> 51)
+            .collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes.dedup();
+        // Out of 8192 slots, uniformly random hashes would fill about 3800.
+        assert!(hashes.len() > 3500, "{} distinct slots", hashes.len());
+    }
+
+    #[test]
+    fn grows_and_finds_everything() {
+        let names = names(5000);
+        let mut map = NameMap::default();
+        for (i, name) in names.iter().enumerate() {
+            assert!(map.get_mut(name).is_none());
+            map.insert(name, i);
+        }
+        assert_eq!(map.len(), names.len());
+        assert_eq!(map.slots.len(), 32768);
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(map.get_mut(name), Some(&mut { i }));
+        }
+        assert!(map.get_mut(b"Station-05000-observatory").is_none());
+        let in_order = map.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(in_order, names);
+    }
+
+    #[test]
+    fn shared_prefixes_and_short_names() {
+        let names: [&[u8]; 9] = [
+            b"",
+            b"a",
+            b"a\0",
+            b"Station",
+            b"Station1",
+            b"Station12",
+            b"Station2",
+            b"Station12345678",
+            b"Station1234567890",
+        ];
+        let mut map = NameMap::default();
+        for (i, name) in names.iter().enumerate() {
+            map.insert(name, i);
+        }
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(map.get_mut(name), Some(&mut { i }));
+        }
+    }
+
+    #[test]
+    fn tells_apart_names_differing_in_one_byte() {
+        let mut names = Vec::new();
+        for len in 0..=48 {
+            names.push(vec![b'a'; len]);
+            for position in 0..len {
+                let mut name = vec![b'a'; len];
+                name[position] = b'b';
+                names.push(name);
+            }
+        }
+        let mut map = NameMap::default();
+        for (i, name) in names.iter().enumerate() {
+            map.insert(name, i);
+        }
+        assert_eq!(map.len(), names.len());
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(map.get_mut(name), Some(&mut { i }));
+        }
+    }
+
+    #[test]
+    fn boxes_long_names() {
+        let inline = [b'x'; INLINE_LEN];
+        let boxed = [b'x'; INLINE_LEN + 1];
+        let longer = [b'x'; 100];
+        let mut map = NameMap::default();
+        map.insert(&inline, 1);
+        map.insert(&boxed, 2);
+        map.insert(&longer, 3);
+        map.insert(&boxed, 4);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get_mut(&boxed), Some(&mut 4));
+        let unboxed = map.slots.capacity() * size_of::<u32>()
+            + map.entries.capacity() * size_of::<(Name, i32)>();
+        assert_eq!(map.table_size() - unboxed, 131);
+        assert_eq!(
+            map.into_iter().collect::<Vec<_>>(),
+            [
+                (inline.to_vec(), 1),
+                (boxed.to_vec(), 4),
+                (longer.to_vec(), 3)
+            ]
+        );
+    }
+}
@@ -0,0 +1,616 @@
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+};
+
+use crate::{
+    columnar::{write_arrow, write_parquet},
+    Aggregation, Rounding,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// The 1BRC `{name=min/mean/max, ...}` line.
+    #[default]
+    Text,
+    /// An array of `{"station", "min", ...}` objects, with a `"histogram"`
+    /// array of `[bucket_lo, bucket_hi, count]` when asked for.
+    Json,
+    /// Comma-separated values with a header row. Histograms are written
+    /// instead of the columns, one `station,bucket_lo,bucket_hi,count` row
+    /// per bucket.
+    Csv,
+    /// Tab-separated values with a header row, histograms as for csv.
+    Tsv,
+    /// An Arrow IPC file, histograms as for csv.
+    Arrow,
+    /// A Parquet file, histograms as for csv.
+    Parquet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Column {
+    Name,
+    Min,
+    Mean,
+    Max,
+    Count,
+    Sum,
+    Variance,
+    Stddev,
+}
+
+impl Column {
+    pub(crate) fn header(self) -> &'static str {
+        match self {
+            Column::Name => "station",
+            Column::Min => "min",
+            Column::Mean => "mean",
+            Column::Max => "max",
+            Column::Count => "count",
+            Column::Sum => "sum",
+            Column::Variance => "variance",
+            Column::Stddev => "stddev",
+        }
+    }
+}
+
+/// A column as written: one of [`Column`], the mean as rounded with the
+/// chosen mode, or a percentile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Field {
+    Column(Column),
+    Mean(Rounding),
+    Percentile(f64),
+}
+
+impl Field {
+    pub(crate) fn header(self) -> Cow<'static, str> {
+        match self {
+            Field::Column(column) => column.header().into(),
+            Field::Mean(_) => Column::Mean.header().into(),
+            Field::Percentile(percentile) => format!("p{percentile}").into(),
+        }
+    }
+
+    /// The value of a mean or percentile field, in the aggregation's
+    /// fixed-point units.
+    pub(crate) fn temperature(self, aggregation: &Aggregation) -> Option<i32> {
+        match self {
+            Field::Column(_) => None,
+            Field::Mean(rounding) => Some(aggregation.rounded_mean(rounding)),
+            Field::Percentile(percentile) => {
+                let value = aggregation.percentile(percentile);
+                Some(value.expect("Checked by write_results"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    pub format: Format,
+    /// Columns to write, or the format's defaults: name, min, mean and max
+    /// for text, all but the variance for the others. The text format always starts
+    /// with the name.
+    pub columns: Option<Vec<Column>>,
+    /// Percentiles to write after the columns, as `p50`, `p99.9` and so on.
+    /// They need the aggregations to keep histograms, see
+    /// [`Aggregator::histograms`](crate::Aggregator::histograms).
+    pub percentiles: Vec<f64>,
+    /// Width of the histogram buckets to write, if any, in the aggregations'
+    /// fixed-point units. Like percentiles, this needs the aggregations to keep histograms.
+    /// Not supported by the text format.
+    pub histogram: Option<u32>,
+    /// How to round the mean to the aggregations' scale.
+    pub rounding: Rounding,
+    /// Write names through [`escape_name`] in the text, JSON, CSV and TSV
+    /// formats, rather than as they are or, for JSON, with U+FFFD.
+    pub escape_names: bool,
+}
+
+impl OutputOptions {
+    pub fn columns(&self) -> &[Column] {
+        match (&self.columns, self.format) {
+            (Some(columns), _) => columns,
+            (None, Format::Text) => &[Column::Name, Column::Min, Column::Mean, Column::Max],
+            (None, _) => &[
+                Column::Name,
+                Column::Min,
+                Column::Mean,
+                Column::Max,
+                Column::Count,
+                Column::Sum,
+                Column::Stddev,
+            ],
+        }
+    }
+
+    fn fields(&self) -> Vec<Field> {
+        let columns = self.columns().iter().map(|&column| match column {
+            Column::Mean => Field::Mean(self.rounding),
+            column => Field::Column(column),
+        });
+        let percentiles = self
+            .percentiles
+            .iter()
+            .map(|&percentile| Field::Percentile(percentile));
+        columns.chain(percentiles).collect()
+    }
+}
+
+pub fn write_results(
+    writer: &mut impl Write,
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    options: &OutputOptions,
+) -> io::Result<()> {
+    let needs_histograms = !options.percentiles.is_empty() || options.histogram.is_some();
+    if needs_histograms
+        && name_aggregations
+            .iter()
+            .any(|(_, aggregation)| !aggregation.has_histogram())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Percentiles and histograms need the aggregator to keep histograms",
+        ));
+    }
+    let fields = &options.fields();
+    let histogram = options.histogram;
+    let escape = options.escape_names;
+    match (options.format, histogram) {
+        (Format::Text, Some(_)) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Histograms cannot be written in the text format",
+        )),
+        (Format::Text, None) => write_text(writer, name_aggregations, fields, escape),
+        (Format::Json, _) => write_json(writer, name_aggregations, fields, histogram, escape),
+        (Format::Csv, None) => write_delimited(writer, name_aggregations, fields, b',', escape),
+        (Format::Tsv, None) => write_delimited(writer, name_aggregations, fields, b'\t', escape),
+        (Format::Csv, Some(width)) => {
+            write_delimited_buckets(writer, name_aggregations, width, b',', escape)
+        }
+        (Format::Tsv, Some(width)) => {
+            write_delimited_buckets(writer, name_aggregations, width, b'\t', escape)
+        }
+        (Format::Arrow, _) => write_arrow(writer, name_aggregations, fields, histogram),
+        (Format::Parquet, _) => write_parquet(writer, name_aggregations, fields, histogram),
+    }
+}
+
+fn write_text(
+    writer: &mut impl Write,
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    fields: &[Field],
+    escape: bool,
+) -> io::Result<()> {
+    writer.write_all(b"{")?;
+    if let Some(((first_name, first_aggregation), rest)) = name_aggregations.split_first() {
+        push_aggregation(writer, first_name, first_aggregation, fields, escape)?;
+        for (name, aggregation) in rest {
+            writer.write_all(b", ")?;
+            push_aggregation(writer, name, aggregation, fields, escape)?;
+        }
+    }
+    writer.write_all(b"}")?;
+    Ok(())
+}
+
+fn push_aggregation(
+    writer: &mut impl Write,
+    name: &[u8],
+    aggregation: &Aggregation,
+    fields: &[Field],
+    escape: bool,
+) -> io::Result<()> {
+    writer.write_all(&name_bytes(name, escape))?;
+    writer.write_all(b"=")?;
+    let values = fields
+        .iter()
+        .filter(|&&field| field != Field::Column(Column::Name));
+    for (i, &field) in values.enumerate() {
+        if i > 0 {
+            writer.write_all(b"/")?;
+        }
+        push_value(writer, aggregation, field)?;
+    }
+    Ok(())
+}
+
+fn write_json(
+    writer: &mut impl Write,
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    fields: &[Field],
+    histogram: Option<u32>,
+    escape: bool,
+) -> io::Result<()> {
+    writer.write_all(b"[")?;
+    for (i, (name, aggregation)) in name_aggregations.iter().enumerate() {
+        writer.write_all(if i == 0 { b"\n  {" } else { b",\n  {" })?;
+        for (j, &field) in fields.iter().enumerate() {
+            if j > 0 {
+                writer.write_all(b", ")?;
+            }
+            write!(writer, "\"{}\": ", field.header())?;
+            match field {
+                Field::Column(Column::Name) => push_json_string(writer, &name_bytes(name, escape))?,
+                _ => push_value(writer, aggregation, field)?,
+            }
+        }
+        if let Some(width) = histogram {
+            if !fields.is_empty() {
+                writer.write_all(b", ")?;
+            }
+            writer.write_all(b"\"histogram\": [")?;
+            let buckets = aggregation
+                .buckets(width)
+                .expect("Checked by write_results");
+            for (j, bucket) in buckets.iter().enumerate() {
+                if j > 0 {
+                    writer.write_all(b", ")?;
+                }
+                writer.write_all(b"[")?;
+                push_float(writer, bucket.low, aggregation.scale())?;
+                writer.write_all(b", ")?;
+                push_float(writer, bucket.high, aggregation.scale())?;
+                write!(writer, ", {}]", bucket.count)?;
+            }
+            writer.write_all(b"]")?;
+        }
+        writer.write_all(b"}")?;
+    }
+    writer.write_all(b"\n]\n")?;
+    Ok(())
+}
+
+fn write_delimited(
+    writer: &mut impl Write,
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    fields: &[Field],
+    delimiter: u8,
+    escape: bool,
+) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(&[delimiter])?;
+        }
+        writer.write_all(field.header().as_bytes())?;
+    }
+    writer.write_all(b"\n")?;
+    for (name, aggregation) in name_aggregations {
+        for (i, &field) in fields.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(&[delimiter])?;
+            }
+            match field {
+                Field::Column(Column::Name) => {
+                    push_delimited_field(writer, &name_bytes(name, escape), delimiter)?
+                }
+                _ => push_value(writer, aggregation, field)?,
+            }
+        }
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Writes the long format histograms, one row per station and bucket.
+fn write_delimited_buckets(
+    writer: &mut impl Write,
+    name_aggregations: &[(Vec<u8>, Aggregation)],
+    width: u32,
+    delimiter: u8,
+    escape: bool,
+) -> io::Result<()> {
+    let delimiter = delimiter as char;
+    writeln!(
+        writer,
+        "station{delimiter}bucket_lo{delimiter}bucket_hi{delimiter}count"
+    )?;
+    for (name, aggregation) in name_aggregations {
+        let name = name_bytes(name, escape);
+        for bucket in aggregation
+            .buckets(width)
+            .expect("Checked by write_results")
+        {
+            push_delimited_field(writer, &name, delimiter as u8)?;
+            write!(writer, "{delimiter}")?;
+            push_float(writer, bucket.low, aggregation.scale())?;
+            write!(writer, "{delimiter}")?;
+            push_float(writer, bucket.high, aggregation.scale())?;
+            writeln!(writer, "{delimiter}{}", bucket.count)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a numeric field of `aggregation`.
+fn push_value(writer: &mut impl Write, aggregation: &Aggregation, field: Field) -> io::Result<()> {
+    let Field::Column(column) = field else {
+        let value = field.temperature(aggregation).expect("Not a column");
+        return push_float(writer, value.into(), aggregation.scale());
+    };
+    match column {
+        Column::Name => unreachable!("The name is not a value"),
+        Column::Min => push_float(writer, aggregation.min().into(), aggregation.scale()),
+        Column::Mean => push_float(writer, aggregation.mean().into(), aggregation.scale()),
+        Column::Max => push_float(writer, aggregation.max().into(), aggregation.scale()),
+        Column::Count => write!(writer, "{}", aggregation.count()),
+        Column::Sum => push_float(writer, aggregation.sum(), aggregation.scale()),
+        // One decimal place more than the temperatures, like 1BRC's two.
+        Column::Variance => write!(
+            writer,
+            "{:.*}",
+            aggregation.scale() as usize + 1,
+            aggregation.variance()
+        ),
+        Column::Stddev => write!(
+            writer,
+            "{:.*}",
+            aggregation.scale() as usize + 1,
+            aggregation.stddev()
+        ),
+    }
+}
+
+/// Writes `field`, quoted as per RFC 4180 if it contains the delimiter, a
+/// quote or a line break.
+fn push_delimited_field(writer: &mut impl Write, field: &[u8], delimiter: u8) -> io::Result<()> {
+    if !field
+        .iter()
+        .any(|&b| b == delimiter || b == b'"' || b == b'\n' || b == b'\r')
+    {
+        return writer.write_all(field);
+    }
+    writer.write_all(b"\"")?;
+    for part in field.split_inclusive(|&b| b == b'"') {
+        writer.write_all(part)?;
+        if part.ends_with(b"\"") {
+            writer.write_all(b"\"")?;
+        }
+    }
+    writer.write_all(b"\"")?;
+    Ok(())
+}
+
+/// `name` with backslashes doubled and each byte of invalid UTF-8 as `\xNN`,
+/// so that it is valid UTF-8 and the original bytes can still be recovered.
+pub fn escape_name(name: &[u8]) -> Cow<'_, [u8]> {
+    if !name.contains(&b'\\') && std::str::from_utf8(name).is_ok() {
+        return Cow::Borrowed(name);
+    }
+    let mut escaped = Vec::with_capacity(name.len() + 8);
+    for chunk in name.utf8_chunks() {
+        for part in chunk.valid().as_bytes().split_inclusive(|&b| b == b'\\') {
+            escaped.extend_from_slice(part);
+            if part.ends_with(b"\\") {
+                escaped.push(b'\\');
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.extend_from_slice(format!("\\x{byte:02x}").as_bytes());
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn name_bytes(name: &[u8], escape: bool) -> Cow<'_, [u8]> {
+    if escape {
+        escape_name(name)
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Writes `bytes` as a quoted JSON string. Invalid UTF-8 becomes U+FFFD.
+fn push_json_string(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            '\r' => writer.write_all(b"\\r")?,
+            '\t' => writer.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => writer.write_all(c.encode_utf8(&mut [0; 4]).as_bytes())?,
+        }
+    }
+    writer.write_all(b"\"")?;
+    Ok(())
+}
+
+/// Writes the fixed-point `value` with `scale` decimal places, through a fast
+/// path for the default single one.
+fn push_float(writer: &mut impl Write, mut value: i64, scale: u8) -> io::Result<()> {
+    if scale != 1 {
+        let divisor = 10_u64.pow(scale as u32);
+        let sign = if value < 0 { "-" } else { "" };
+        let (integer, fraction) = (
+            value.unsigned_abs() / divisor,
+            value.unsigned_abs() % divisor,
+        );
+        return match scale {
+            0 => write!(writer, "{sign}{integer}"),
+            _ => write!(
+                writer,
+                "{sign}{integer}.{fraction:0width$}",
+                width = scale as usize
+            ),
+        };
+    }
+    if value < 0 {
+        writer.write_all(b"-")?;
+        value = -value;
+    }
+    if value >= 1000 {
+        write!(writer, "{}", value / 100)?;
+    } else if value >= 100 {
+        writer.write_all(&[(value / 100) as u8 + b'0'])?;
+    }
+    writer.write_all(&[((value / 10) % 10) as u8 + b'0'])?;
+    writer.write_all(b".")?;
+    writer.write_all(&[(value % 10) as u8 + b'0'])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Recovers the bytes of a name written by [`escape_name`].
+    fn unescape(escaped: &[u8]) -> Vec<u8> {
+        let mut name = Vec::new();
+        let mut rest = escaped;
+        while let Some((&byte, tail)) = rest.split_first() {
+            rest = match (byte, tail) {
+                (b'\\', [b'\\', tail @ ..]) => {
+                    name.push(b'\\');
+                    tail
+                }
+                (b'\\', [b'x', high, low, tail @ ..]) => {
+                    let hex = [*high, *low];
+                    let hex = std::str::from_utf8(&hex).expect("Hex digits");
+                    name.push(u8::from_str_radix(hex, 16).expect("Hex digits"));
+                    tail
+                }
+                (b'\\', _) => panic!("Dangling backslash in {escaped:?}"),
+                _ => {
+                    name.push(byte);
+                    tail
+                }
+            };
+        }
+        name
+    }
+
+    fn written(name: &[u8], format: Format, escape_names: bool) -> String {
+        let mut aggregation = Aggregation::new(1, false);
+        aggregation.update(15);
+        let options = OutputOptions {
+            format,
+            columns: Some(vec![Column::Name, Column::Max]),
+            escape_names,
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        write_results(&mut output, &[(name.to_vec(), aggregation)], &options)
+            .expect("Writing to a Vec");
+        String::from_utf8(output).expect("UTF-8 output")
+    }
+
+    #[test]
+    fn escapes_names_reversibly() {
+        // Every name of up to 4 bytes from backslashes, what escapes look like,
+        // and valid and invalid UTF-8.
+        let alphabet = [b'\\', b'x', b'f', b'a', 0xff, 0xc3, 0xa9];
+        let mut names = vec![Vec::new()];
+        for len in 1..=4 {
+            let longer = names
+                .iter()
+                .filter(|name| name.len() == len - 1)
+                .flat_map(|name| {
+                    alphabet.iter().map(|&byte| {
+                        let mut name = name.clone();
+                        name.push(byte);
+                        name
+                    })
+                })
+                .collect::<Vec<_>>();
+            names.extend(longer);
+        }
+        let mut escaped_names = Vec::new();
+        for name in &names {
+            let escaped = escape_name(name);
+            assert!(std::str::from_utf8(&escaped).is_ok(), "{name:?}");
+            assert_eq!(unescape(&escaped), *name);
+            escaped_names.push(escaped.into_owned());
+        }
+        escaped_names.sort_unstable();
+        escaped_names.dedup();
+        assert_eq!(escaped_names.len(), names.len());
+    }
+
+    #[test]
+    fn escapes_only_backslashes_and_invalid_utf8() {
+        assert!(matches!(escape_name("Zürich".as_bytes()), Cow::Borrowed(_)));
+        assert_eq!(&*escape_name(b"a\\xff"), b"a\\\\xff");
+        assert_eq!(&*escape_name(b"a\xff"), b"a\\xff");
+        assert_eq!(&*escape_name(b"Z\xc3\xbc\xc3"), "Zü\\xc3".as_bytes());
+        assert_eq!(&*escape_name(b"\\\xe2\x82"), b"\\\\\\xe2\\x82");
+    }
+
+    #[test]
+    fn quotes_json_strings() {
+        let json = |bytes: &[u8]| {
+            let mut output = Vec::new();
+            push_json_string(&mut output, bytes).expect("Writing to a Vec");
+            String::from_utf8(output).expect("UTF-8 output")
+        };
+        assert_eq!(json(b"Berlin"), r#""Berlin""#);
+        assert_eq!(json(br#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(json(b"\n\r\t\x01\x1f"), r#""\n\r\t\u0001\u001f""#);
+        assert_eq!(json("Zürich".as_bytes()), "\"Zürich\"");
+        assert_eq!(json(b"a\xffb"), "\"a\u{fffd}b\"");
+    }
+
+    #[test]
+    fn quotes_delimited_fields() {
+        let field = |bytes: &[u8], delimiter| {
+            let mut output = Vec::new();
+            push_delimited_field(&mut output, bytes, delimiter).expect("Writing to a Vec");
+            String::from_utf8(output).expect("UTF-8 output")
+        };
+        assert_eq!(field(b"Berlin", b','), "Berlin");
+        assert_eq!(field(b"Berlin, DE", b','), r#""Berlin, DE""#);
+        assert_eq!(field(b"Berlin, DE", b'\t'), "Berlin, DE");
+        assert_eq!(field(b"Berlin\tDE", b'\t'), "\"Berlin\tDE\"");
+        assert_eq!(field(br#"a "b""#, b','), r#""a ""b""""#);
+        assert_eq!(field(b"a\nb", b','), "\"a\nb\"");
+        assert_eq!(field(b"a\rb", b','), "\"a\rb\"");
+    }
+
+    #[test]
+    fn writes_spread_to_one_more_decimal_place() {
+        let spread = |values: &[i32], scale| {
+            let mut aggregation = Aggregation::new(scale, false);
+            for &value in values {
+                aggregation.update(value);
+            }
+            [Column::Variance, Column::Stddev].map(|column| {
+                let mut output = Vec::new();
+                push_value(&mut output, &aggregation, Field::Column(column))
+                    .expect("Writing to a Vec");
+                String::from_utf8(output).expect("UTF-8 output")
+            })
+        };
+        assert_eq!(spread(&[10, 20], 1), ["0.25", "0.50"]);
+        assert_eq!(spread(&[1, 2, 4], 0), ["1.6", "1.2"]);
+        assert_eq!(spread(&[1000, 1001], 3), ["0.0000", "0.0005"]);
+        assert_eq!(spread(&[1000, 1030], 3), ["0.0002", "0.0150"]);
+    }
+
+    #[test]
+    fn writes_escaped_names() {
+        let name = b"a\\\"b\",\xff";
+        let row = |format, escape| {
+            written(name, format, escape)
+                .lines()
+                .nth(1)
+                .map(String::from)
+        };
+        assert_eq!(
+            row(Format::Csv, true).as_deref(),
+            Some(r#""a\\""b"",\xff",1.5"#)
+        );
+        assert_eq!(
+            row(Format::Json, true).as_deref(),
+            Some(r#"  {"station": "a\\\\\"b\",\\xff", "max": 1.5}"#)
+        );
+        assert_eq!(
+            row(Format::Json, false).as_deref(),
+            Some(r#"  {"station": "a\\\"b\",�", "max": 1.5}"#)
+        );
+        assert_eq!(written(name, Format::Text, true), r#"{a\\"b",\xff=1.5}"#);
+    }
+}
@@ -0,0 +1,271 @@
+/// Where the name and temperature are in a line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub delimiter: u8,
+    /// Without them the temperature is everything after the last delimiter
+    /// and the name everything before it.
+    pub columns: Option<Columns>,
+}
+
+/// Zero-based columns of a line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Columns {
+    /// Joined with the delimiter into the name, in this order.
+    pub keys: Vec<usize>,
+    /// Without it the temperature is everything after the last delimiter,
+    /// and the key columns are taken from before it.
+    pub value: Option<usize>,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            delimiter: b';',
+            columns: None,
+        }
+    }
+}
+
+/// Parses a line into the name and the temperature as a fixed-point integer
+/// with `scale` decimal places. Temperatures may have fewer decimal places
+/// than that, but not more, and must fit in an `i32`. A trailing `\r` is
+/// ignored. Names made of several columns are built in `key`.
+pub(crate) fn parse_line<'a>(
+    line: &'a [u8],
+    scale: u8,
+    layout: &Layout,
+    key: &'a mut Vec<u8>,
+) -> Option<(&'a [u8], i32)> {
+    if scale == 1 && *layout == Layout::default() {
+        if let Some(parsed) = parse_classic(line) {
+            return Some(parsed);
+        }
+    }
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let (name, number) = match &layout.columns {
+        None => {
+            let separator = memchr::memrchr(layout.delimiter, line)?;
+            (&line[..separator], &line[separator + 1..])
+        }
+        Some(columns) => {
+            let (line, number) = match columns.value {
+                Some(_) => (line, None),
+                None => {
+                    let separator = memchr::memrchr(layout.delimiter, line)?;
+                    (&line[..separator], Some(&line[separator + 1..]))
+                }
+            };
+            let field = |column| line.split(|&b| b == layout.delimiter).nth(column);
+            let name = match columns.keys[..] {
+                [column] => field(column)?,
+                _ => {
+                    key.clear();
+                    for (i, &column) in columns.keys.iter().enumerate() {
+                        if i > 0 {
+                            key.push(layout.delimiter);
+                        }
+                        key.extend_from_slice(field(column)?);
+                    }
+                    &key[..]
+                }
+            };
+            let number = match columns.value {
+                Some(column) => field(column)?,
+                None => number?,
+            };
+            (name, number)
+        }
+    };
+    Some((name, parse_number(number, scale)?))
+}
+
+/// The fast path for the 1BRC format, `-99.9` to `99.9` in tenths.
+fn parse_classic(line: &[u8]) -> Option<(&[u8], i32)> {
+    let (name, is_negative, tens, ones, decimal) = match line {
+        [name @ .., b';', b'-', tens, ones, b'.', decimal] => (name, true, *tens, *ones, *decimal),
+        [name @ .., b';', b'-', ones, b'.', decimal] => (name, true, b'0', *ones, *decimal),
+        [name @ .., b';', tens, ones, b'.', decimal] => (name, false, *tens, *ones, *decimal),
+        [name @ .., b';', ones, b'.', decimal] => (name, false, b'0', *ones, *decimal),
+        _ => return None,
+    };
+    if ![tens, ones, decimal].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let zero = b'0' as i32;
+    let value =
+        ((tens as i32 - zero) * 100) + ((ones as i32 - zero) * 10) + (decimal as i32 - zero);
+    if is_negative {
+        Some((name, -value))
+    } else {
+        Some((name, value))
+    }
+}
+
+fn parse_number(number: &[u8], scale: u8) -> Option<i32> {
+    let (is_negative, number) = match number {
+        [b'-', rest @ ..] => (true, rest),
+        _ => (false, number),
+    };
+    let (integer, fraction) = match memchr::memchr(b'.', number) {
+        Some(dot) if dot + 1 < number.len() => (&number[..dot], &number[dot + 1..]),
+        Some(_) => return None,
+        None => (number, &[][..]),
+    };
+    if integer.is_empty() || fraction.len() > scale as usize {
+        return None;
+    }
+    let mut value: i64 = 0;
+    for &digit in integer.iter().chain(fraction) {
+        if !digit.is_ascii_digit() || value > i32::MAX as i64 {
+            return None;
+        }
+        value = value * 10 + (digit - b'0') as i64;
+    }
+    let value = value.checked_mul(10_i64.pow((scale as usize - fraction.len()) as u32))?;
+    let value = if is_negative { -value } else { value };
+    i32::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str, scale: u8, layout: &Layout) -> Option<(String, i32)> {
+        let mut key = Vec::new();
+        let (name, value) = parse_line(line.as_bytes(), scale, layout, &mut key)?;
+        Some((String::from_utf8(name.to_vec()).expect("UTF-8 name"), value))
+    }
+
+    fn parsed(name: &str, value: i32) -> Option<(String, i32)> {
+        Some((name.to_string(), value))
+    }
+
+    fn columns(keys: &[usize], value: Option<usize>) -> Layout {
+        Layout {
+            delimiter: b';',
+            columns: Some(Columns {
+                keys: keys.to_vec(),
+                value,
+            }),
+        }
+    }
+
+    #[test]
+    fn parses_classic_lines() {
+        let cases: [(&[u8], i32); 5] = [
+            (b"Hamburg;12.0", 120),
+            (b"Hamburg;-9.9", -99),
+            (b"Hamburg;99.9", 999),
+            (b"Hamburg;-0.1", -1),
+            (b"St. John's;0.0", 0),
+        ];
+        for (line, value) in cases {
+            let name = &line[..memchr::memrchr(b';', line).expect("delimiter")];
+            assert_eq!(parse_classic(line), Some((name, value)));
+        }
+        for line in [
+            "Hamburg;1.23",
+            "Hamburg;1",
+            "Hamburg;100.0",
+            "Hamburg;1x.0",
+            "12.0",
+        ] {
+            assert_eq!(parse_classic(line.as_bytes()), None, "{line}");
+        }
+    }
+
+    #[test]
+    fn pads_and_checks_the_scale() {
+        assert_eq!(parse_number(b"1", 2), Some(100));
+        assert_eq!(parse_number(b"1.5", 3), Some(1500));
+        assert_eq!(parse_number(b"-0.5", 1), Some(-5));
+        assert_eq!(parse_number(b"-0", 0), Some(0));
+        assert_eq!(parse_number(b"007", 0), Some(7));
+        assert_eq!(parse_number(b"2.147483647", 9), Some(i32::MAX));
+        assert_eq!(parse_number(b"1.23", 1), None);
+        assert_eq!(parse_number(b"1.5", 0), None);
+        assert_eq!(parse_number(b"3", 9), None);
+    }
+
+    #[test]
+    fn rejects_numbers_out_of_range() {
+        assert_eq!(parse_number(b"2147483647", 0), Some(i32::MAX));
+        assert_eq!(parse_number(b"-2147483648", 0), Some(i32::MIN));
+        assert_eq!(parse_number(b"2147483648", 0), None);
+        assert_eq!(parse_number(b"-2147483649", 0), None);
+        assert_eq!(parse_number(b"214748364.8", 1), None);
+        assert_eq!(parse_number(b"99999999999999999999999", 0), None);
+    }
+
+    #[test]
+    fn rejects_malformed_numbers() {
+        for number in [
+            "", "-", ".5", "-.5", "5.", "5..0", "1.2.3", "+1", "1e3", " 1", "1 ", "--1", "0x1",
+        ] {
+            assert_eq!(parse_number(number.as_bytes(), 2), None, "{number:?}");
+        }
+    }
+
+    #[test]
+    fn parses_lines_outside_the_fast_path() {
+        let layout = Layout::default();
+        assert_eq!(parse("Berlin;1.5\r", 1, &layout), parsed("Berlin", 15));
+        assert_eq!(parse("Berlin;1.25\r", 2, &layout), parsed("Berlin", 125));
+        assert_eq!(parse("Berlin;100", 1, &layout), parsed("Berlin", 1000));
+        assert_eq!(parse("a;b;-1.5", 1, &layout), parsed("a;b", -15));
+        assert_eq!(parse(";1.5", 1, &layout), parsed("", 15));
+        assert_eq!(parse("Berlin;1.5\r\r", 1, &layout), None);
+        assert_eq!(parse("Berlin\r", 1, &layout), None);
+        assert_eq!(parse("Berlin;", 1, &layout), None);
+        assert_eq!(parse("", 1, &layout), None);
+
+        let commas = Layout {
+            delimiter: b',',
+            columns: None,
+        };
+        assert_eq!(parse("Berlin,1.5", 1, &commas), parsed("Berlin", 15));
+        assert_eq!(parse("Berlin;x,-2", 1, &commas), parsed("Berlin;x", -20));
+        assert_eq!(parse("Berlin;1.5", 1, &commas), None);
+    }
+
+    #[test]
+    fn extracts_columns() {
+        let line = "DE;Berlin;x;1.5";
+        assert_eq!(
+            parse(line, 1, &columns(&[1], Some(3))),
+            parsed("Berlin", 15)
+        );
+        assert_eq!(
+            parse(line, 1, &columns(&[1, 0], Some(3))),
+            parsed("Berlin;DE", 15)
+        );
+        assert_eq!(
+            parse("DE;Berlin;1.5;x", 1, &columns(&[1], Some(2))),
+            parsed("Berlin", 15)
+        );
+        assert_eq!(
+            parse("DE;Berlin;1.5\r", 1, &columns(&[0, 1], Some(2))),
+            parsed("DE;Berlin", 15)
+        );
+        assert_eq!(parse(line, 1, &columns(&[0], Some(4))), None);
+        assert_eq!(parse(line, 1, &columns(&[4], Some(3))), None);
+        assert_eq!(parse(line, 1, &columns(&[0, 4], Some(3))), None);
+        assert_eq!(parse(line, 1, &columns(&[1], Some(2))), None);
+    }
+
+    #[test]
+    fn takes_the_last_column_without_a_value_column() {
+        let line = "DE;Berlin;1.5\r";
+        assert_eq!(parse(line, 1, &columns(&[1], None)), parsed("Berlin", 15));
+        assert_eq!(
+            parse(line, 1, &columns(&[0, 1], None)),
+            parsed("DE;Berlin", 15)
+        );
+        assert_eq!(
+            parse("DE;Berlin;x;1.5", 1, &columns(&[2], None)),
+            parsed("x", 15)
+        );
+        assert_eq!(parse(line, 1, &columns(&[2], None)), None);
+        assert_eq!(parse("Berlin", 1, &columns(&[0], None)), None);
+    }
+}
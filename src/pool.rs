@@ -0,0 +1,193 @@
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashSet;
+
+use crate::{process_chunk, ChunkOutcome, Registry, Settings};
+
+/// Input bytes shared between the reader and the workers.
+pub(crate) type Bytes = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// Newline-terminated lines that the workers split between themselves by
+/// claiming `chunk_size` byte chunks from a shared cursor.
+pub(crate) struct Batch {
+    /// Sequence number of the first chunk; chunk `i` gets `seq + i`.
+    pub seq: u64,
+    /// Byte offset of the start of `range` in the whole input.
+    pub offset: u64,
+    pub data: Bytes,
+    pub range: Range<usize>,
+    pub chunk_size: usize,
+    pub next_chunk: AtomicUsize,
+}
+
+impl Batch {
+    pub fn chunks(&self) -> usize {
+        self.range.len().div_ceil(self.chunk_size)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct WorkerState {
+    pub registry: Registry,
+    /// Names the filter turned down, so it only looks at each once.
+    pub filtered_names: FxHashSet<Vec<u8>>,
+    /// Names that are not valid UTF-8, when validating them.
+    pub invalid_names: FxHashSet<Vec<u8>>,
+    pub busy: Duration,
+}
+
+/// Long-lived worker threads, each owning one registry.
+pub(crate) struct Pool {
+    batch_senders: Vec<mpsc::Sender<Arc<Batch>>>,
+    outcome_receiver: mpsc::Receiver<(u64, ChunkOutcome)>,
+    workers: Vec<JoinHandle<()>>,
+    states: Vec<Arc<Mutex<WorkerState>>>,
+}
+
+impl Pool {
+    pub fn new(threads: usize, settings: Arc<Settings>) -> Self {
+        let (outcome_sender, outcome_receiver) = mpsc::channel();
+        let states = (0..threads)
+            .map(|_| Arc::new(Mutex::new(WorkerState::default())))
+            .collect::<Vec<_>>();
+        let (batch_senders, workers) = states
+            .iter()
+            .map(|state| {
+                let (batch_sender, batch_receiver) = mpsc::channel::<Arc<Batch>>();
+                let state = Arc::clone(state);
+                let outcome_sender = outcome_sender.clone();
+                let settings = Arc::clone(&settings);
+                let worker = thread::spawn(move || {
+                    for batch in batch_receiver {
+                        let bytes = &(*batch.data).as_ref()[batch.range.clone()];
+                        loop {
+                            let chunk = batch.next_chunk.fetch_add(1, Ordering::Relaxed);
+                            if chunk >= batch.chunks() {
+                                break;
+                            }
+                            let start = Instant::now();
+                            let lines = lines_starting_in(
+                                bytes,
+                                chunk * batch.chunk_size,
+                                (chunk + 1) * batch.chunk_size,
+                            );
+                            let mut state = state.lock().expect("Worker state lock poisoned");
+                            let outcome = process_chunk(
+                                &mut state,
+                                &bytes[lines.clone()],
+                                batch.offset + lines.start as u64,
+                                &settings,
+                            );
+                            state.busy += start.elapsed();
+                            drop(state);
+                            if outcome_sender
+                                .send((batch.seq + chunk as u64, outcome))
+                                .is_err()
+                            {
+                                return;
+                            }
+                        }
+                    }
+                });
+                (batch_sender, worker)
+            })
+            .unzip();
+        Self {
+            batch_senders,
+            outcome_receiver,
+            workers,
+            states,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.batch_senders.len()
+    }
+
+    pub fn submit(&self, batch: Batch) {
+        let batch = Arc::new(batch);
+        for batch_sender in &self.batch_senders {
+            batch_sender
+                .send(Arc::clone(&batch))
+                .expect("Worker thread panicked");
+        }
+    }
+
+    pub fn outcomes(&self) -> &mpsc::Receiver<(u64, ChunkOutcome)> {
+        &self.outcome_receiver
+    }
+
+    pub fn states(&self) -> &[Arc<Mutex<WorkerState>>] {
+        &self.states
+    }
+
+    /// Stops the workers once their queues are drained and hands back their
+    /// states.
+    pub fn join(mut self) -> Vec<WorkerState> {
+        self.batch_senders.clear();
+        for worker in self.workers.drain(..) {
+            worker.join().expect("Worker thread panicked");
+        }
+        std::mem::take(&mut self.states)
+            .into_iter()
+            .map(|state| {
+                Arc::into_inner(state)
+                    .expect("Workers are stopped")
+                    .into_inner()
+                    .expect("Worker state lock poisoned")
+            })
+            .collect()
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.batch_senders.clear();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The lines of `bytes` that start within `start..end`, so that adjacent
+/// chunks never split or share a line.
+fn lines_starting_in(bytes: &[u8], start: usize, end: usize) -> Range<usize> {
+    let line_start = |at: usize| {
+        if at == 0 {
+            0
+        } else if at >= bytes.len() {
+            bytes.len()
+        } else {
+            memchr::memchr(b'\n', &bytes[at - 1..]).map_or(bytes.len(), |i| at + i)
+        }
+    };
+    let first = line_start(start);
+    first..line_start(end).max(first)
+}
+
+/// A read buffer that goes back to its pool once every worker is done with it.
+pub(crate) struct Buffer {
+    pub data: Vec<u8>,
+    pub len: usize,
+    pub recycle: mpsc::Sender<Vec<u8>>,
+}
+
+impl AsRef<[u8]> for Buffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let _ = self.recycle.send(std::mem::take(&mut self.data));
+    }
+}
@@ -0,0 +1,100 @@
+use std::cmp::Ordering;
+
+use crate::Aggregation;
+
+/// The statistic to order stations by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortBy {
+    /// Name bytes.
+    #[default]
+    Name,
+    /// Exact mean, before rounding.
+    Mean,
+    Max,
+    Min,
+    Count,
+    /// Max minus min.
+    Range,
+}
+
+impl SortBy {
+    fn compare(self, a: &Aggregation, b: &Aggregation) -> Ordering {
+        match self {
+            SortBy::Name => Ordering::Equal,
+            // a.sum / a.count vs b.sum / b.count, without dividing.
+            SortBy::Mean => {
+                (a.sum() as i128 * b.count() as i128).cmp(&(b.sum() as i128 * a.count() as i128))
+            }
+            SortBy::Max => a.max().cmp(&b.max()),
+            SortBy::Min => a.min().cmp(&b.min()),
+            SortBy::Count => a.count().cmp(&b.count()),
+            SortBy::Range => range(a).cmp(&range(b)),
+        }
+    }
+}
+
+fn range(aggregation: &Aggregation) -> i64 {
+    aggregation.max() as i64 - aggregation.min() as i64
+}
+
+/// How names compare, when sorting by name and to break ties.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Collation {
+    /// Name bytes, as in the 1BRC reference.
+    #[default]
+    Byte,
+    /// Unicode code points, invalid UTF-8 as U+FFFD. The same as byte order
+    /// for valid UTF-8.
+    Codepoint,
+    /// The Unicode Collation Algorithm with the CLDR root locale.
+    Uca,
+}
+
+/// How to order and trim the results.
+#[derive(Debug, Clone, Default)]
+pub struct SortOptions {
+    pub sort_by: SortBy,
+    pub descending: bool,
+    /// Only keep the first this many.
+    pub top: Option<usize>,
+    pub collation: Collation,
+}
+
+/// Sorts by `options.sort_by`, ties going by name, and keeps the first
+/// `options.top` if given. Only the kept ones are fully sorted.
+pub(crate) fn sort(name_aggregations: &mut Vec<(Vec<u8>, Aggregation)>, options: &SortOptions) {
+    let SortOptions {
+        sort_by,
+        descending,
+        top,
+        collation,
+    } = *options;
+    let mut collator = (collation == Collation::Uca).then(feruca::Collator::default);
+    let mut compare_names = move |name1: &[u8], name2: &[u8]| match &mut collator {
+        Some(collator) => collator.collate(name1, name2),
+        None if collation == Collation::Codepoint => String::from_utf8_lossy(name1)
+            .chars()
+            .cmp(String::from_utf8_lossy(name2).chars())
+            .then_with(|| name1.cmp(name2)),
+        None => name1.cmp(name2),
+    };
+    let mut compare = |(name1, aggregation1): &(Vec<u8>, Aggregation),
+                       (name2, aggregation2): &(Vec<u8>, Aggregation)| {
+        let by_statistic = sort_by.compare(aggregation1, aggregation2);
+        let mut by_name = || compare_names(name1, name2);
+        match (descending, sort_by) {
+            (true, SortBy::Name) => by_name().reverse(),
+            (true, _) => by_statistic.reverse().then_with(by_name),
+            (false, _) => by_statistic.then_with(by_name),
+        }
+    };
+    if let Some(top) = top.filter(|&top| top < name_aggregations.len()) {
+        if top == 0 {
+            name_aggregations.clear();
+            return;
+        }
+        name_aggregations.select_nth_unstable_by(top - 1, &mut compare);
+        name_aggregations.truncate(top);
+    }
+    name_aggregations.sort_unstable_by(compare);
+}